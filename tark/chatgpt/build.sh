@@ -12,7 +12,7 @@ if ! rustup target list --installed | grep -q "wasm32-unknown-unknown"; then
 fi
 
 # Build in release mode
-cargo build --target wasm32-unknown-unknown --release
+cargo build --target wasm32-unknown-unknown --release "$@"
 
 # Create dist directory
 mkdir -p dist
@@ -26,11 +26,20 @@ const CLIENT_ID: &str = "app_EMoamEEZ73f0CkXaXp7hrann";
 const TOKEN_URL: &str = "https://auth.openai.com/oauth/token";
 /// ChatGPT Codex API endpoint
 const CODEX_API_ENDPOINT: &str = "https://chatgpt.com/backend-api/codex/responses";
+/// `originator` header sent when CHATGPT_ORIGINATOR is unset
+const DEFAULT_ORIGINATOR: &str = "opencode";
+/// Every storage key owned by this plugin (cleared by `plugin_reset`)
+const STORAGE_KEYS: &[&str] = &["state", START_CHECK_KEY];
+/// Storage key of the last `provider_start` connectivity check
+const START_CHECK_KEY: &str = "start_check";
+/// Prefix of the backups written when stored state fails to parse
+const CORRUPT_STATE_PREFIX: &str = "state.corrupt.";
 
 // =============================================================================
 // Host Function Imports (provided by tark)
 // =============================================================================
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:storage")]
 extern "C" {
     #[link_name = "get"]
@@ -38,8 +47,15 @@ extern "C" {
 
     #[link_name = "set"]
     fn storage_set_raw(key_ptr: i32, key_len: i32, val_ptr: i32, val_len: i32) -> i32;
+
+    #[link_name = "delete"]
+    fn storage_delete_raw(key_ptr: i32, key_len: i32) -> i32;
+
+    #[link_name = "keys"]
+    fn storage_keys_raw(prefix_ptr: i32, prefix_len: i32, ret_ptr: i32) -> i32;
 }
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:http")]
 extern "C" {
     #[link_name = "post"]
@@ -54,6 +70,7 @@ extern "C" {
     ) -> i32;
 }
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:log")]
 extern "C" {
     #[link_name = "info"]
@@ -64,18 +81,119 @@ extern "C" {
     fn log_debug_raw(msg_ptr: i32, msg_len: i32);
 }
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:env")]
 extern "C" {
     #[link_name = "get"]
     fn env_get_raw(name_ptr: i32, name_len: i32, ret_ptr: i32) -> i32;
 }
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:fs")]
 extern "C" {
     #[link_name = "read"]
     fn fs_read_raw(path_ptr: i32, path_len: i32, ret_ptr: i32) -> i32;
 }
 
+// =============================================================================
+// Host Function Stubs (native unit tests)
+// =============================================================================
+
+/// Stand-ins for the tark host imports so the plugin logic can be unit tested
+/// natively. Every call reports "unavailable" without touching `ret_ptr`;
+/// tests that need a host value set it in the thread-local mocks below.
+#[cfg(test)]
+mod host_stubs {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    thread_local! {
+        /// Variables `env_get` sees on this thread
+        pub static MOCK_ENV: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+        /// Host storage as seen from this thread
+        pub static MOCK_STORAGE: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+        /// Answers `http_post(url, body)` with the host's `{status, headers, body}` JSON
+        pub static MOCK_HTTP: RefCell<Option<MockHttp>> = RefCell::new(None);
+    }
+
+    pub type MockHttp = Box<dyn Fn(&str, &str) -> String>;
+
+    pub fn mock_env_get(name: &str) -> Option<String> {
+        MOCK_ENV.with(|env| env.borrow().get(name).cloned())
+    }
+
+    pub fn mock_storage_get(key: &str) -> Option<String> {
+        MOCK_STORAGE.with(|storage| storage.borrow().get(key).cloned())
+    }
+
+    pub fn mock_storage_set(key: &str, value: &str) -> bool {
+        MOCK_STORAGE.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert(key.to_string(), value.to_string())
+        });
+        true
+    }
+
+    pub fn mock_storage_delete(key: &str) -> bool {
+        MOCK_STORAGE.with(|storage| storage.borrow_mut().remove(key).is_some())
+    }
+
+    pub fn mock_http_post(url: &str, body: &str) -> Option<String> {
+        MOCK_HTTP.with(|http| http.borrow().as_ref().map(|respond| respond(url, body)))
+    }
+
+    pub unsafe fn storage_get_raw(_key_ptr: i32, _key_len: i32, _ret_ptr: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn storage_set_raw(
+        _key_ptr: i32,
+        _key_len: i32,
+        _val_ptr: i32,
+        _val_len: i32,
+    ) -> i32 {
+        -1
+    }
+
+    pub unsafe fn storage_delete_raw(_key_ptr: i32, _key_len: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn storage_keys_raw(_prefix_ptr: i32, _prefix_len: i32, _ret_ptr: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn http_post_raw(
+        _url_ptr: i32,
+        _url_len: i32,
+        _body_ptr: i32,
+        _body_len: i32,
+        _headers_ptr: i32,
+        _headers_len: i32,
+        _ret_ptr: i32,
+    ) -> i32 {
+        -1
+    }
+
+    pub unsafe fn log_info_raw(_msg_ptr: i32, _msg_len: i32) {}
+
+    pub unsafe fn log_error_raw(_msg_ptr: i32, _msg_len: i32) {}
+
+    pub unsafe fn log_debug_raw(_msg_ptr: i32, _msg_len: i32) {}
+
+    pub unsafe fn env_get_raw(_name_ptr: i32, _name_len: i32, _ret_ptr: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn fs_read_raw(_path_ptr: i32, _path_len: i32, _ret_ptr: i32) -> i32 {
+        -1
+    }
+}
+
+#[cfg(test)]
+use host_stubs::*;
+
 // =============================================================================
 // Types
 // =============================================================================
@@ -90,6 +208,9 @@ struct TokenResponse {
     id_token: Option<String>,
     #[serde(default)]
     expires_in: Option<u64>,
+    /// Absolute Unix timestamp (seconds); some tools return this instead of `expires_in`
+    #[serde(default)]
+    expires_at: Option<u64>,
 }
 
 /// Stored OAuth credentials
@@ -118,7 +239,15 @@ struct PluginState {
 // Memory Management
 // =============================================================================
 
-static mut RETURN_BUFFER: [u8; 131072] = [0u8; 131072]; // 128KB for responses
+/// Capacity of RETURN_BUFFER, chosen with the `buffer-*` Cargo features
+#[cfg(feature = "buffer-512k")]
+const RETURN_BUFFER_SIZE: usize = 512 * 1024;
+#[cfg(all(feature = "buffer-256k", not(feature = "buffer-512k")))]
+const RETURN_BUFFER_SIZE: usize = 256 * 1024;
+#[cfg(not(any(feature = "buffer-256k", feature = "buffer-512k")))]
+const RETURN_BUFFER_SIZE: usize = 128 * 1024;
+
+static mut RETURN_BUFFER: [u8; RETURN_BUFFER_SIZE] = [0u8; RETURN_BUFFER_SIZE];
 static mut ENV_BUFFER: [u8; 256] = [0u8; 256];
 
 fn return_buffer_ptr() -> i32 {
@@ -130,7 +259,8 @@ fn env_buffer_ptr() -> i32 {
 }
 
 unsafe fn return_buffer_bytes(len: i32) -> &'static [u8] {
-    std::slice::from_raw_parts(std::ptr::addr_of!(RETURN_BUFFER).cast::<u8>(), len as usize)
+    let len = (len as usize).min(RETURN_BUFFER_SIZE);
+    std::slice::from_raw_parts(std::ptr::addr_of!(RETURN_BUFFER).cast::<u8>(), len)
 }
 
 unsafe fn env_buffer_bytes(len: i32) -> &'static [u8] {
@@ -172,6 +302,10 @@ fn log_debug(msg: &str) {
 }
 
 fn storage_get(key: &str) -> Option<String> {
+    #[cfg(test)]
+    if let Some(value) = mock_storage_get(key) {
+        return Some(value);
+    }
     unsafe {
         let ret = storage_get_raw(key.as_ptr() as i32, key.len() as i32, return_buffer_ptr());
         if ret > 0 {
@@ -183,6 +317,10 @@ fn storage_get(key: &str) -> Option<String> {
 }
 
 fn storage_set(key: &str, value: &str) -> bool {
+    #[cfg(test)]
+    if mock_storage_set(key, value) {
+        return true;
+    }
     unsafe {
         storage_set_raw(
             key.as_ptr() as i32,
@@ -193,7 +331,35 @@ fn storage_set(key: &str, value: &str) -> bool {
     }
 }
 
+fn storage_delete(key: &str) -> bool {
+    #[cfg(test)]
+    if mock_storage_delete(key) {
+        return true;
+    }
+    unsafe { storage_delete_raw(key.as_ptr() as i32, key.len() as i32) == 0 }
+}
+
+/// Stored keys starting with `prefix` (the host returns a JSON array)
+fn storage_keys(prefix: &str) -> Vec<String> {
+    unsafe {
+        let ret = storage_keys_raw(
+            prefix.as_ptr() as i32,
+            prefix.len() as i32,
+            return_buffer_ptr(),
+        );
+        if ret > 0 {
+            serde_json::from_slice(return_buffer_bytes(ret)).unwrap_or_default()
+        } else {
+            Vec::new()
+        }
+    }
+}
+
 fn http_post(url: &str, body: &str, headers: &[(String, String)]) -> Option<String> {
+    #[cfg(test)]
+    if let Some(raw) = mock_http_post(url, body) {
+        return Some(raw);
+    }
     let headers_json = serde_json::to_string(headers).unwrap_or_default();
     unsafe {
         let ret = http_post_raw(
@@ -213,7 +379,278 @@ fn http_post(url: &str, body: &str, headers: &[(String, String)]) -> Option<Stri
     }
 }
 
+/// How a host HTTP call turned out, so retry/refresh/error decisions are
+/// made the same way everywhere
+#[derive(Debug, PartialEq)]
+enum HttpOutcome {
+    /// 2xx with the response body
+    Success(String),
+    /// 408 or 5xx, worth retrying later
+    Retryable(u16),
+    /// 401, the credentials were rejected
+    Unauthorized,
+    /// 401/403 with `insufficient_scope`: the token is valid but lacks a
+    /// scope, so refreshing it will not help
+    InsufficientScope(u16),
+    /// 429, with the Retry-After delay in seconds when the server sent one
+    RateLimited { retry_after: Option<u64> },
+    /// Any other non-2xx status
+    ClientError { status: u16, body: String },
+    /// The host could not complete the request
+    TransportError,
+}
+
+impl std::fmt::Display for HttpOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpOutcome::Success(_) => write!(f, "success"),
+            HttpOutcome::Retryable(status) => write!(f, "HTTP {} (retryable)", status),
+            HttpOutcome::Unauthorized => write!(f, "HTTP 401 (unauthorized)"),
+            HttpOutcome::InsufficientScope(status) => {
+                write!(f, "HTTP {} (insufficient_scope)", status)
+            }
+            HttpOutcome::RateLimited {
+                retry_after: Some(secs),
+            } => write!(f, "HTTP 429 (retry after {}s)", secs),
+            HttpOutcome::RateLimited { retry_after: None } => write!(f, "HTTP 429"),
+            HttpOutcome::ClientError { status, .. } => write!(f, "HTTP {}", status),
+            HttpOutcome::TransportError => write!(f, "transport error"),
+        }
+    }
+}
+
+/// Classify the host's `{status, headers, body}` wrapper. A response that is
+/// not wrapped is treated as a successful raw body.
+fn classify_response(raw: &str) -> HttpOutcome {
+    let parsed: serde_json::Value = match serde_json::from_str(raw) {
+        Ok(v) => v,
+        Err(_) => return HttpOutcome::Success(raw.to_string()),
+    };
+    let body = match parsed.get("body").and_then(|b| b.as_str()) {
+        Some(body) => body.to_string(),
+        None => return HttpOutcome::Success(raw.to_string()),
+    };
+    let status = parsed.get("status").and_then(|s| s.as_u64()).unwrap_or(0) as u16;
+    match status {
+        200..=299 => HttpOutcome::Success(body),
+        401 | 403 if auth_error_code(&parsed, &body).as_deref() == Some("insufficient_scope") => {
+            HttpOutcome::InsufficientScope(status)
+        }
+        401 => HttpOutcome::Unauthorized,
+        429 => HttpOutcome::RateLimited {
+            retry_after: response_header(&parsed, "retry-after")
+                .and_then(|v| v.trim().parse().ok()),
+        },
+        408 | 500..=599 => HttpOutcome::Retryable(status),
+        0 => HttpOutcome::TransportError,
+        _ => HttpOutcome::ClientError { status, body },
+    }
+}
+
+/// Bearer error code of a rejected request: the `error` parameter of
+/// `WWW-Authenticate` (RFC 6750), else an `error` string or `error.code` in
+/// the JSON body
+fn auth_error_code(parsed: &serde_json::Value, body: &str) -> Option<String> {
+    let from_header = response_header(parsed, "www-authenticate").and_then(|challenge| {
+        challenge.split(',').find_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            let key = key.trim().rsplit(' ').next()?;
+            (key == "error").then(|| value.trim().trim_matches('"').to_string())
+        })
+    });
+    from_header.or_else(|| {
+        let json: serde_json::Value = serde_json::from_str(body).ok()?;
+        let error = json.get("error")?;
+        error
+            .as_str()
+            .or_else(|| error.get("code").and_then(|c| c.as_str()))
+            .map(String::from)
+    })
+}
+
+/// Header from the host wrapper, which sends either `[[name, value]]` pairs
+/// or a `{name: value}` object
+fn response_header(parsed: &serde_json::Value, name: &str) -> Option<String> {
+    match parsed.get("headers")? {
+        serde_json::Value::Array(pairs) => pairs.iter().find_map(|pair| {
+            let key = pair.get(0)?.as_str()?;
+            if key.eq_ignore_ascii_case(name) {
+                pair.get(1)?.as_str().map(String::from)
+            } else {
+                None
+            }
+        }),
+        serde_json::Value::Object(map) => map
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, value)| value.as_str().map(String::from)),
+        _ => None,
+    }
+}
+
+/// Why a token refresh failed
+#[derive(Debug)]
+enum RefreshError {
+    /// The refresh token was rejected; only re-authenticating helps
+    InvalidGrant(String),
+    /// The grant lacks a required scope; only re-authenticating helps
+    InsufficientScope(String),
+    /// The token endpoint answered with a JSON OAuth error other than invalid_grant
+    OAuth {
+        error: String,
+        description: Option<String>,
+    },
+    /// The token endpoint answered with something that is not JSON (an
+    /// HTML gateway page, plain text, ...)
+    NonJson {
+        status: u16,
+        content_type: Option<String>,
+        body: String,
+    },
+    /// The request failed without a usable body
+    Http(HttpOutcome),
+}
+
+impl std::fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefreshError::InvalidGrant(description) => {
+                write!(f, "refresh token rejected (invalid_grant: {})", description)
+            }
+            RefreshError::InsufficientScope(description) => {
+                write!(
+                    f,
+                    "token lacks a required scope (insufficient_scope: {})",
+                    description
+                )
+            }
+            RefreshError::OAuth { error, description } => match description {
+                Some(description) => write!(f, "auth server error {}: {}", error, description),
+                None => write!(f, "auth server error {}", error),
+            },
+            RefreshError::NonJson {
+                status,
+                content_type,
+                body,
+            } => write!(
+                f,
+                "auth server returned non-JSON (HTTP {}, content-type {}): {}",
+                status,
+                content_type.as_deref().unwrap_or("unknown"),
+                body
+            ),
+            RefreshError::Http(outcome) => write!(f, "{}", outcome),
+        }
+    }
+}
+
+/// Default and upper bound for `PLUGIN_LOG_BODY_BYTES`
+const DEFAULT_LOG_BODY_BYTES: usize = 500;
+const MAX_LOG_BODY_BYTES: usize = 16 * 1024;
+
+/// How much of an unparseable response body to keep in logs and errors
+fn log_body_bytes() -> usize {
+    env_get("PLUGIN_LOG_BODY_BYTES")
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(DEFAULT_LOG_BODY_BYTES)
+        .min(MAX_LOG_BODY_BYTES)
+}
+
+/// Leading part of `body`, at most `max_bytes` long and cut on a char boundary
+fn body_excerpt(body: &str, max_bytes: usize) -> String {
+    let mut end = body.len().min(max_bytes);
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    body[..end].to_string()
+}
+
+/// Parse a token endpoint response into `T`, telling OAuth errors apart
+/// from bodies that are not JSON at all
+fn parse_token_response<T: serde::de::DeserializeOwned>(raw: &str) -> Result<T, RefreshError> {
+    let outcome = classify_response(raw);
+    let wrapper: Option<serde_json::Value> = serde_json::from_str(raw)
+        .ok()
+        .filter(|v: &serde_json::Value| v.get("body").and_then(|b| b.as_str()).is_some());
+    let (status, content_type, body) = match &wrapper {
+        Some(w) => (
+            w.get("status").and_then(|s| s.as_u64()).unwrap_or(0) as u16,
+            response_header(w, "content-type"),
+            w.get("body")
+                .and_then(|b| b.as_str())
+                .unwrap_or("")
+                .to_string(),
+        ),
+        None => (200, None, raw.to_string()),
+    };
+
+    if let HttpOutcome::Success(_) = outcome {
+        if let Ok(token) = serde_json::from_str::<T>(&body) {
+            return Ok(token);
+        }
+    }
+
+    match serde_json::from_str::<serde_json::Value>(&body) {
+        Ok(json) => {
+            // OAuth errors are `{error, error_description}`; OpenAI also
+            // answers `{error: {message, type, code}}`, labelled by `code`
+            // (or `type` when `code` is null) with the message kept whole.
+            let error_object = json.get("error").filter(|e| e.is_object());
+            let error = json.get("error").and_then(|e| e.as_str()).or_else(|| {
+                error_object
+                    .and_then(|e| e.get("code").and_then(|c| c.as_str()))
+                    .or_else(|| {
+                        error_object
+                            .and_then(|e| e.get("type"))
+                            .and_then(|t| t.as_str())
+                    })
+                    .or(error_object.map(|_| "error"))
+            });
+            let description = json
+                .get("error_description")
+                .or_else(|| error_object.and_then(|e| e.get("message")))
+                .and_then(|d| d.as_str())
+                .map(String::from);
+            match error {
+                Some("invalid_grant") => Err(RefreshError::InvalidGrant(
+                    description.unwrap_or_else(|| "no description".to_string()),
+                )),
+                Some("insufficient_scope") => Err(RefreshError::InsufficientScope(
+                    description.unwrap_or_else(|| "no description".to_string()),
+                )),
+                Some(error) => Err(RefreshError::OAuth {
+                    error: error.to_string(),
+                    description,
+                }),
+                None if matches!(outcome, HttpOutcome::Success(_)) => Err(RefreshError::OAuth {
+                    error: "invalid_response".to_string(),
+                    description: Some("no access_token in response".to_string()),
+                }),
+                None if matches!(outcome, HttpOutcome::InsufficientScope(_)) => Err(
+                    RefreshError::InsufficientScope("from WWW-Authenticate".to_string()),
+                ),
+                None => Err(RefreshError::Http(outcome)),
+            }
+        }
+        Err(_) if matches!(outcome, HttpOutcome::InsufficientScope(_)) => Err(
+            RefreshError::InsufficientScope("from WWW-Authenticate".to_string()),
+        ),
+        Err(_) if body.trim().is_empty() && !matches!(outcome, HttpOutcome::Success(_)) => {
+            Err(RefreshError::Http(outcome))
+        }
+        Err(_) => Err(RefreshError::NonJson {
+            status,
+            content_type,
+            body: body_excerpt(&body, log_body_bytes()),
+        }),
+    }
+}
+
 fn env_get(name: &str) -> Option<String> {
+    #[cfg(test)]
+    if let Some(value) = mock_env_get(name) {
+        return Some(value);
+    }
     unsafe {
         let len = env_get_raw(name.as_ptr() as i32, name.len() as i32, env_buffer_ptr());
         if len <= 0 {
@@ -229,14 +666,88 @@ fn env_get(name: &str) -> Option<String> {
 
 /// Read a file from the filesystem (if allowed by capabilities)
 fn fs_read(path: &str) -> Option<String> {
+    match fs_read_result(path) {
+        Ok(content) => Some(content),
+        Err(code) => {
+            log_debug(&format!("fs_read({}) failed with code {}", path, code));
+            None
+        }
+    }
+}
+
+/// `fs_read_result` code for a path outside `fs_allowlist()`, kept apart
+/// from the host's -2 (not in `capabilities.fs_read`)
+const FS_NOT_IN_ALLOWLIST: i32 = -4;
+
+/// Read a file, keeping the host's error code on failure:
+/// 0 = empty, -1 = invalid path, -2 = permission denied, -3 = read error
+/// (including not found). Non-UTF-8 content is reported as -3. Paths
+/// outside the plugin's own allowlist fail with FS_NOT_IN_ALLOWLIST.
+fn fs_read_result(path: &str) -> Result<String, i32> {
+    if !path_in_allowlist(path, &fs_allowlist()) {
+        log_fs_refusal(path);
+        return Err(FS_NOT_IN_ALLOWLIST);
+    }
     unsafe {
         let ret = fs_read_raw(path.as_ptr() as i32, path.len() as i32, return_buffer_ptr());
         if ret > 0 {
-            String::from_utf8(return_buffer_bytes(ret).to_vec()).ok()
+            String::from_utf8(return_buffer_bytes(ret).to_vec()).map_err(|_| -3)
         } else {
-            log_debug(&format!("fs_read({}) failed with code {}", path, ret));
-            None
+            Err(ret)
+        }
+    }
+}
+
+/// Paths the plugin may read. `PLUGIN_FS_ALLOWLIST` replaces the default
+/// (the credentials and endpoints files) with a colon-separated list; an
+/// entry ending in `/` allows everything below that directory.
+fn fs_allowlist() -> Vec<String> {
+    match env_get("PLUGIN_FS_ALLOWLIST") {
+        Some(list) => list
+            .split(':')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => vec![credentials_path(), endpoints_path()],
+    }
+}
+
+fn path_in_allowlist(path: &str, allowlist: &[String]) -> bool {
+    if path.split('/').any(|part| part == "..") {
+        return false;
+    }
+    allowlist.iter().any(|entry| {
+        if entry.ends_with('/') {
+            path.starts_with(entry.as_str())
+        } else {
+            path == entry
         }
+    })
+}
+
+/// Paths whose allowlist refusal has been logged already
+static LOGGED_FS_REFUSALS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+/// Log that `path` is outside the fs allowlist, once per path: the endpoints
+/// file is looked up on every token request
+fn log_fs_refusal(path: &str) {
+    let first = LOGGED_FS_REFUSALS
+        .lock()
+        .map(|mut logged| {
+            if logged.iter().any(|p| p == path) {
+                false
+            } else {
+                logged.push(path.to_string());
+                true
+            }
+        })
+        .unwrap_or(true);
+    if first {
+        log_error(&format!(
+            "Refusing to read '{}': not in the plugin fs allowlist (set PLUGIN_FS_ALLOWLIST to change it)",
+            path
+        ));
     }
 }
 
@@ -246,11 +757,52 @@ fn fs_read(path: &str) -> Option<String> {
 
 fn load_state() -> PluginState {
     match storage_get("state") {
-        Some(s) => serde_json::from_str(&s).unwrap_or_default(),
+        Some(s) => serde_json::from_str(&s).unwrap_or_else(|e| recover_corrupt_state(&s, &e)),
         None => PluginState::default(),
     }
 }
 
+/// Handle stored state that no longer parses: keep the raw JSON under
+/// `state.corrupt.<ts>`, salvage what we can, and persist the result so the
+/// backup only happens once.
+fn recover_corrupt_state(raw: &str, err: &serde_json::Error) -> PluginState {
+    let backup_key = format!("{}{}", CORRUPT_STATE_PREFIX, now_secs());
+    storage_set(&backup_key, raw);
+    log_error(&format!(
+        "Stored state is corrupt ({}); backed up to '{}'",
+        err, backup_key
+    ));
+
+    let state = salvage_state(raw).unwrap_or_else(|| {
+        log_error("Nothing salvageable in corrupt state, starting fresh");
+        PluginState::default()
+    });
+    save_state(&state);
+    state
+}
+
+/// Leniently pull tokens out of a state blob that failed strict parsing
+fn salvage_state(raw: &str) -> Option<PluginState> {
+    let value: serde_json::Value = serde_json::from_str(raw).ok()?;
+    let creds = value
+        .get("credentials")
+        .filter(|c| c.is_object())
+        .unwrap_or(&value);
+    let text = |key: &str| creds.get(key).and_then(|x| x.as_str()).map(String::from);
+
+    let credentials = OAuthCredentials {
+        access_token: text("access_token")?,
+        refresh_token: text("refresh_token"),
+        id_token: text("id_token"),
+        expires_at: creds.get("expires_at").and_then(|x| x.as_u64()),
+        account_id: text("account_id"),
+    };
+    log_info("Recovered credentials from corrupt state");
+    Some(PluginState {
+        credentials: Some(credentials),
+    })
+}
+
 fn save_state(state: &PluginState) {
     if let Ok(json) = serde_json::to_string(state) {
         storage_set("state", &json);
@@ -268,7 +820,7 @@ fn credentials_path() -> String {
             return path;
         }
     }
-    
+
     // Default path
     if let Some(home) = env_get("HOME") {
         format!("{}/.config/tark/chatgpt_oauth.json", home)
@@ -280,20 +832,35 @@ fn credentials_path() -> String {
 fn load_credentials_from_file() -> Option<OAuthCredentials> {
     let path = credentials_path();
     log_debug(&format!("Loading credentials from: {}", path));
-    
+
     let content = fs_read(&path)?;
     let creds: OAuthCredentials = serde_json::from_str(&content).ok()?;
-    
+
     log_info(&format!(
         "Loaded credentials: token_len={}, has_refresh={}, has_account_id={}",
         creds.access_token.len(),
         creds.refresh_token.is_some(),
         creds.account_id.is_some()
     ));
-    
+
     Some(creds)
 }
 
+/// Where credentials would come from right now: "storage" (tark-managed
+/// state), "file" (credentials file not yet loaded) or "none"
+fn credential_source() -> &'static str {
+    if load_state().credentials.is_some() {
+        "storage"
+    } else if fs_read(&credentials_path())
+        .and_then(|content| serde_json::from_str::<OAuthCredentials>(&content).ok())
+        .is_some()
+    {
+        "file"
+    } else {
+        "none"
+    }
+}
+
 // =============================================================================
 // JWT Parsing (for Account ID extraction)
 // =============================================================================
@@ -304,7 +871,7 @@ fn parse_jwt_claims(token: &str) -> Option<serde_json::Value> {
     if parts.len() != 3 {
         return None;
     }
-    
+
     // Decode base64url payload (second part)
     let payload = base64url_decode(parts[1])?;
     let payload_str = String::from_utf8(payload).ok()?;
@@ -319,7 +886,7 @@ fn base64url_decode(input: &str) -> Option<Vec<u8>> {
         3 => format!("{}=", input),
         _ => input.to_string(),
     };
-    
+
     // Convert base64url to standard base64
     let standard: String = padded
         .chars()
@@ -329,43 +896,138 @@ fn base64url_decode(input: &str) -> Option<Vec<u8>> {
             c => c,
         })
         .collect();
-    
+
     // Simple base64 decode
     base64_decode(&standard)
 }
 
 fn base64_decode(input: &str) -> Option<Vec<u8>> {
     const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
-    
+
     let mut output = Vec::new();
     let mut buffer: u32 = 0;
     let mut bits_collected = 0;
-    
+
     for c in input.chars() {
         if c == '=' {
             break;
         }
-        
+
         let value = ALPHABET.iter().position(|&x| x == c as u8)? as u32;
         buffer = (buffer << 6) | value;
         bits_collected += 6;
-        
+
         if bits_collected >= 8 {
             bits_collected -= 8;
             output.push((buffer >> bits_collected) as u8);
             buffer &= (1 << bits_collected) - 1;
         }
     }
-    
+
     Some(output)
 }
 
-/// Extract account ID from JWT tokens
-/// Extract account_id from a single JWT token string
+/// Where `extract_account_id` looks for the ChatGPT account id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AccountIdSource {
+    /// Claims of the id_token
+    IdToken,
+    /// Claims of the access_token
+    AccessToken,
+    /// account_id saved alongside the tokens
+    Stored,
+}
+
+const DEFAULT_ACCOUNT_ID_ORDER: [AccountIdSource; 3] = [
+    AccountIdSource::IdToken,
+    AccountIdSource::AccessToken,
+    AccountIdSource::Stored,
+];
+
+/// Resolve the account id lookup order from `CHATGPT_ACCOUNT_ID_ORDER`
+/// (comma-separated `id_token`, `access_token`, `stored`). Sources left out
+/// are not tried.
+fn account_id_order() -> Vec<AccountIdSource> {
+    let mut order = Vec::new();
+    if let Some(raw) = env_get("CHATGPT_ACCOUNT_ID_ORDER") {
+        for name in raw.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let source = match name.to_ascii_lowercase().as_str() {
+                "id_token" => AccountIdSource::IdToken,
+                "access_token" => AccountIdSource::AccessToken,
+                "stored" => AccountIdSource::Stored,
+                _ => {
+                    log_error(&format!(
+                        "CHATGPT_ACCOUNT_ID_ORDER: ignoring unknown source '{}'",
+                        name
+                    ));
+                    continue;
+                }
+            };
+            if !order.contains(&source) {
+                order.push(source);
+            }
+        }
+        if order.is_empty() {
+            log_error("CHATGPT_ACCOUNT_ID_ORDER has no valid sources, using default order");
+        }
+    }
+    if order.is_empty() {
+        order = DEFAULT_ACCOUNT_ID_ORDER.to_vec();
+    }
+    order
+}
+
+/// Pick the workspace from a token's `organizations` claim: `CHATGPT_ORG_ID`
+/// by id, else `CHATGPT_ORG_INDEX` by position, else the first one
+fn select_organization(orgs: &[serde_json::Value]) -> Option<&serde_json::Value> {
+    if let Some(wanted) = env_get("CHATGPT_ORG_ID") {
+        match orgs
+            .iter()
+            .find(|o| o.get("id").and_then(|v| v.as_str()) == Some(wanted.as_str()))
+        {
+            Some(org) => return Some(org),
+            None => log_error(&format!(
+                "CHATGPT_ORG_ID {} not among the token's organizations",
+                mask_id(&wanted)
+            )),
+        }
+    }
+    if let Some(raw) = env_get("CHATGPT_ORG_INDEX") {
+        match raw.trim().parse::<usize>().ok().and_then(|i| orgs.get(i)) {
+            Some(org) => return Some(org),
+            None => log_error(&format!(
+                "CHATGPT_ORG_INDEX '{}' is not a valid index into {} organizations",
+                raw,
+                orgs.len()
+            )),
+        }
+    }
+    orgs.first()
+}
+
+fn org_selection_configured() -> bool {
+    env_get("CHATGPT_ORG_ID").is_some() || env_get("CHATGPT_ORG_INDEX").is_some()
+}
+
+/// Account id from a single JWT. An explicit org selection wins over the
+/// `chatgpt_account_id` claim; otherwise the claim wins over `organizations`.
 fn extract_account_id_from_token(token: &str) -> Option<String> {
     let claims = parse_jwt_claims(token)?;
-    
-    // Check various claim locations
+    let org_id = || {
+        claims
+            .get("organizations")
+            .and_then(|v| v.as_array())
+            .and_then(|orgs| select_organization(orgs))
+            .and_then(|org| org.get("id"))
+            .and_then(|v| v.as_str())
+            .map(String::from)
+    };
+
+    if org_selection_configured() {
+        if let Some(id) = org_id() {
+            return Some(id);
+        }
+    }
     if let Some(id) = claims.get("chatgpt_account_id").and_then(|v| v.as_str()) {
         return Some(id.to_string());
     }
@@ -374,53 +1036,133 @@ fn extract_account_id_from_token(token: &str) -> Option<String> {
             return Some(id.to_string());
         }
     }
-    if let Some(orgs) = claims.get("organizations").and_then(|v| v.as_array()) {
-        if let Some(first) = orgs.first() {
-            if let Some(id) = first.get("id").and_then(|v| v.as_str()) {
-                return Some(id.to_string());
-            }
-        }
-    }
-    None
+    org_id()
+}
+
+/// Account id from the first source in `account_id_order()` that has one
+fn account_id_from_tokens(
+    id_token: Option<&str>,
+    access_token: &str,
+    stored: Option<&str>,
+) -> Option<String> {
+    account_id_order()
+        .into_iter()
+        .find_map(|source| match source {
+            AccountIdSource::IdToken => id_token.and_then(extract_account_id_from_token),
+            AccountIdSource::AccessToken => extract_account_id_from_token(access_token),
+            AccountIdSource::Stored => stored.map(String::from),
+        })
 }
 
 fn extract_account_id(creds: &OAuthCredentials) -> Option<String> {
-    // Try id_token first
-    if let Some(ref id_token) = creds.id_token {
-        if let Some(claims) = parse_jwt_claims(id_token) {
-            // Check various claim locations
-            if let Some(id) = claims.get("chatgpt_account_id").and_then(|v| v.as_str()) {
-                return Some(id.to_string());
-            }
-            if let Some(auth) = claims.get("https://api.openai.com/auth") {
-                if let Some(id) = auth.get("chatgpt_account_id").and_then(|v| v.as_str()) {
-                    return Some(id.to_string());
-                }
-            }
-            if let Some(orgs) = claims.get("organizations").and_then(|v| v.as_array()) {
-                if let Some(first) = orgs.first() {
-                    if let Some(id) = first.get("id").and_then(|v| v.as_str()) {
-                        return Some(id.to_string());
-                    }
-                }
-            }
+    account_id_from_tokens(
+        creds.id_token.as_deref(),
+        &creds.access_token,
+        creds.account_id.as_deref(),
+    )
+}
+
+/// Claim objects to search: the token root and OpenAI's namespaced auth claim
+fn openai_claim_scopes(claims: &serde_json::Value) -> Vec<&serde_json::Value> {
+    let mut scopes = vec![claims];
+    if let Some(auth) = claims.get("https://api.openai.com/auth") {
+        scopes.push(auth);
+    }
+    scopes
+}
+
+/// Pick the organization id from claims: the workspace chosen with
+/// `CHATGPT_ORG_ID`/`CHATGPT_ORG_INDEX` (the one the account id header names),
+/// else explicit `organization_id`, else the default entry of
+/// `organizations`, else its first entry
+fn organization_from_claims(claims: &serde_json::Value) -> Option<String> {
+    if org_selection_configured() {
+        let selected = openai_claim_scopes(claims).into_iter().find_map(|scope| {
+            scope
+                .get("organizations")
+                .and_then(|v| v.as_array())
+                .and_then(|orgs| select_organization(orgs))
+                .and_then(|org| org.get("id"))
+                .and_then(|v| v.as_str())
+                .map(String::from)
+        });
+        if selected.is_some() {
+            return selected;
         }
     }
-    
-    // Try access_token
-    if let Some(claims) = parse_jwt_claims(&creds.access_token) {
-        if let Some(id) = claims.get("chatgpt_account_id").and_then(|v| v.as_str()) {
+    for scope in openai_claim_scopes(claims) {
+        if let Some(id) = scope.get("organization_id").and_then(|v| v.as_str()) {
             return Some(id.to_string());
         }
-        if let Some(auth) = claims.get("https://api.openai.com/auth") {
-            if let Some(id) = auth.get("chatgpt_account_id").and_then(|v| v.as_str()) {
+        if let Some(orgs) = scope.get("organizations").and_then(|v| v.as_array()) {
+            let org = orgs
+                .iter()
+                .find(|o| {
+                    o.get("is_default")
+                        .and_then(|v| v.as_bool())
+                        .unwrap_or(false)
+                })
+                .or_else(|| orgs.first());
+            if let Some(id) = org.and_then(|o| o.get("id")).and_then(|v| v.as_str()) {
                 return Some(id.to_string());
             }
         }
     }
-    
-    // Use stored account_id
-    creds.account_id.clone()
+    None
+}
+
+fn project_from_claims(claims: &serde_json::Value) -> Option<String> {
+    openai_claim_scopes(claims)
+        .into_iter()
+        .find_map(|scope| scope.get("project_id").and_then(|v| v.as_str()))
+        .map(|id| id.to_string())
+}
+
+/// OAuth scopes granted to a JWT (`scp` array or space-separated `scope`)
+fn token_scopes(token: &str) -> Option<Vec<String>> {
+    let claims = parse_jwt_claims(token)?;
+    openai_claim_scopes(&claims).into_iter().find_map(|scope| {
+        if let Some(list) = scope.get("scp").and_then(|v| v.as_array()) {
+            return Some(
+                list.iter()
+                    .filter_map(|s| s.as_str().map(String::from))
+                    .collect(),
+            );
+        }
+        scope
+            .get("scope")
+            .and_then(|v| v.as_str())
+            .map(|s| s.split_whitespace().map(String::from).collect())
+    })
+}
+
+/// Scopes the Codex backend checks for; a token without them is answered
+/// with an `insufficient_scope` challenge naming the scope
+const CODEX_REQUIRED_SCOPES: &[&str] = &["model.request"];
+
+/// Required Codex scopes missing from `granted`
+fn missing_codex_scopes(granted: &[String]) -> Vec<String> {
+    CODEX_REQUIRED_SCOPES
+        .iter()
+        .filter(|scope| !granted.iter().any(|g| g == *scope))
+        .map(|scope| scope.to_string())
+        .collect()
+}
+
+/// Resolve the OpenAI organization and project ids for request headers.
+/// `OPENAI_ORG` / `OPENAI_PROJECT` override whatever the JWTs carry.
+fn extract_org_and_project(creds: &OAuthCredentials) -> (Option<String>, Option<String>) {
+    let claims: Vec<serde_json::Value> = creds
+        .id_token
+        .iter()
+        .chain(std::iter::once(&creds.access_token))
+        .filter_map(|token| parse_jwt_claims(token))
+        .collect();
+
+    let organization =
+        env_get("OPENAI_ORG").or_else(|| claims.iter().find_map(organization_from_claims));
+    let project = env_get("OPENAI_PROJECT").or_else(|| claims.iter().find_map(project_from_claims));
+    (organization, project)
 }
 
 // =============================================================================
@@ -434,58 +1176,117 @@ fn now_secs() -> u64 {
         .unwrap_or(0)
 }
 
+/// Absolute timestamps above 10^12 are milliseconds; anything else is seconds
+fn timestamp_secs(ts: u64) -> u64 {
+    if ts > 1_000_000_000_000 {
+        ts / 1000
+    } else {
+        ts
+    }
+}
+
 fn is_expired(creds: &OAuthCredentials) -> bool {
-    creds.expires_at
-        .map(|exp| now_secs() >= exp.saturating_sub(300)) // 5 minute buffer
+    creds
+        .expires_at
+        .map(|exp| now_secs() >= timestamp_secs(exp).saturating_sub(300)) // 5 minute buffer
         .unwrap_or(false)
 }
 
-fn refresh_access_token(refresh_token: &str) -> Option<OAuthCredentials> {
+/// Endpoint overrides from `~/.config/tark/chatgpt_endpoints.json`
+#[derive(Debug, Default, Deserialize)]
+struct EndpointConfig {
+    #[serde(default)]
+    token_url: Option<String>,
+    #[serde(default)]
+    api_endpoint: Option<String>,
+}
+
+fn endpoints_path() -> String {
+    match env_get("HOME") {
+        Some(home) => format!("{}/.config/tark/chatgpt_endpoints.json", home),
+        None => "~/.config/tark/chatgpt_endpoints.json".to_string(),
+    }
+}
+
+/// Load the endpoints file. A missing file means no overrides; an invalid
+/// one is logged and ignored.
+fn endpoint_config() -> EndpointConfig {
+    let path = endpoints_path();
+    let Some(content) = fs_read(&path) else {
+        return EndpointConfig::default();
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        log_error(&format!("Ignoring invalid endpoints file {}: {}", path, e));
+        EndpointConfig::default()
+    })
+}
+
+/// Env var first, then the endpoints file, then the built-in default
+fn pick_endpoint(env: Option<String>, file: Option<String>, default: &str) -> String {
+    env.into_iter()
+        .chain(file)
+        .map(|url| url.trim().to_string())
+        .find(|url| !url.is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
+
+/// Token endpoint, overridable via `CHATGPT_TOKEN_URL` (e.g. a local mock in CI)
+/// or `token_url` in the endpoints file
+fn token_url() -> String {
+    pick_endpoint(
+        env_get("CHATGPT_TOKEN_URL"),
+        endpoint_config().token_url,
+        TOKEN_URL,
+    )
+}
+
+/// Codex endpoint, overridable via `CHATGPT_CODEX_ENDPOINT` or `api_endpoint`
+/// in the endpoints file
+fn codex_endpoint() -> String {
+    pick_endpoint(
+        env_get("CHATGPT_CODEX_ENDPOINT"),
+        endpoint_config().api_endpoint,
+        CODEX_API_ENDPOINT,
+    )
+}
+
+fn refresh_access_token(refresh_token: &str) -> Result<OAuthCredentials, RefreshError> {
     log_debug("Refreshing ChatGPT OAuth token...");
-    
+
     let body = format!(
         "grant_type=refresh_token&refresh_token={}&client_id={}",
         urlencoding_encode(refresh_token),
         CLIENT_ID
     );
-    
+
     let headers = vec![(
         "Content-Type".to_string(),
         "application/x-www-form-urlencoded".to_string(),
     )];
-    
-    let response = http_post(TOKEN_URL, &body, &headers)?;
-    let parsed: serde_json::Value = serde_json::from_str(&response).ok()?;
-    
-    // Handle HTTP wrapper from host
-    let body_str = if let Some(body) = parsed.get("body").and_then(|b| b.as_str()) {
-        let status = parsed.get("status").and_then(|s| s.as_u64()).unwrap_or(0);
-        if status != 200 {
-            log_error(&format!("Token refresh failed: HTTP {}", status));
-            return None;
-        }
-        body.to_string()
-    } else {
-        response
-    };
-    
-    let token_data: TokenResponse = serde_json::from_str(&body_str).ok()?;
-    
-    let expires_at = token_data.expires_in.map(|s| now_secs() + s);
-    
+
+    let response = http_post(&token_url(), &body, &headers)
+        .ok_or(RefreshError::Http(HttpOutcome::TransportError))?;
+    let token_data: TokenResponse = parse_token_response(&response)?;
+
+    let expires_at = token_data
+        .expires_at
+        .or_else(|| token_data.expires_in.map(|s| now_secs() + s));
+
     let mut new_creds = OAuthCredentials {
         access_token: token_data.access_token,
-        refresh_token: token_data.refresh_token.or_else(|| Some(refresh_token.to_string())),
+        refresh_token: token_data
+            .refresh_token
+            .or_else(|| Some(refresh_token.to_string())),
         id_token: token_data.id_token,
         expires_at,
         account_id: None,
     };
-    
+
     // Extract account ID from new tokens
     new_creds.account_id = extract_account_id(&new_creds);
-    
+
     log_info("ChatGPT token refreshed successfully");
-    Some(new_creds)
+    Ok(new_creds)
 }
 
 /// Simple URL encoding for form data
@@ -508,7 +1309,7 @@ fn urlencoding_encode(input: &str) -> String {
 
 fn get_valid_token() -> Result<(String, Option<String>), String> {
     let mut state = load_state();
-    
+
     // First, try to load from file if no credentials in state
     if state.credentials.is_none() {
         if let Some(creds) = load_credentials_from_file() {
@@ -516,26 +1317,73 @@ fn get_valid_token() -> Result<(String, Option<String>), String> {
             save_state(&state);
         }
     }
-    
+
     let creds = state.credentials.as_ref().ok_or_else(|| {
         "No credentials stored. Run 'tark auth chatgpt' or manually create ~/.config/tark/chatgpt_oauth.json".to_string()
     })?;
-    
+
     // Extract account ID if not present
-    let account_id = creds.account_id.clone().or_else(|| extract_account_id(creds));
-    
+    let account_id = creds
+        .account_id
+        .clone()
+        .or_else(|| extract_account_id(creds));
+
     // If token is still valid, use it
     if !creds.access_token.is_empty() && !is_expired(creds) {
         return Ok((creds.access_token.clone(), account_id));
     }
-    
+
     // Token expired - try to refresh
     if let Some(refresh) = &creds.refresh_token {
-        if let Some(new_creds) = refresh_access_token(refresh) {
+        let refreshed = match refresh_access_token(refresh) {
+            Ok(new_creds) => Some(new_creds),
+            Err(RefreshError::InvalidGrant(description)) => {
+                log_error(&format!(
+                    "Token refresh failed: invalid_grant ({})",
+                    description
+                ));
+                return Err("Refresh token was rejected (invalid_grant). \
+                     Run 'tark auth chatgpt' to authenticate again."
+                    .to_string());
+            }
+            Err(RefreshError::InsufficientScope(description)) => {
+                log_error(&format!(
+                    "Token refresh failed: insufficient_scope ({})",
+                    description
+                ));
+                return Err(
+                    "ChatGPT token lacks a required scope (insufficient_scope). \
+                     Run 'tark auth chatgpt' to re-authenticate with the required scopes."
+                        .to_string(),
+                );
+            }
+            Err(e) => {
+                log_error(&format!("Token refresh failed: {}", e));
+                None
+            }
+        };
+        if let Some(new_creds) = refreshed {
             let token = new_creds.access_token.clone();
             let new_account_id = new_creds.account_id.clone().or(account_id);
+            // Save first: the refresh may have rotated the refresh token
             state.credentials = Some(new_creds);
             save_state(&state);
+            // Tokens without a scope claim are left for Codex to judge
+            if let Some(granted) = token_scopes(&token) {
+                log_debug(&format!("Refreshed token scopes: {:?}", granted));
+                let missing = missing_codex_scopes(&granted);
+                if !missing.is_empty() {
+                    log_error(&format!(
+                        "Refreshed token lacks Codex scopes: {:?}",
+                        missing
+                    ));
+                    return Err(format!(
+                        "Refreshed ChatGPT token is missing scopes {:?} and would be rejected by Codex. \
+                         Run 'tark auth chatgpt' to re-authenticate with the required scopes.",
+                        missing
+                    ));
+                }
+            }
             return Ok((token, new_account_id));
         }
         // Refresh failed, try existing token anyway
@@ -544,30 +1392,162 @@ fn get_valid_token() -> Result<(String, Option<String>), String> {
             return Ok((creds.access_token.clone(), account_id));
         }
     }
-    
+
     // No refresh token, try existing access token
     if !creds.access_token.is_empty() {
         return Ok((creds.access_token.clone(), account_id));
     }
-    
+
     Err("No valid token available. Run 'tark auth chatgpt' to authenticate.".to_string())
 }
 
-// =============================================================================
-// Provider Plugin Interface - Auth Only
-// =============================================================================
+/// Whether tark should stream Codex responses (SSE). The Codex endpoint
+/// answers `stream: true` requests with SSE; set `CHATGPT_STREAMING=0` to
+/// request plain JSON instead.
+fn streaming_enabled() -> bool {
+    !matches!(
+        env_get("CHATGPT_STREAMING").as_deref(),
+        Some("0") | Some("false") | Some("no")
+    )
+}
 
-/// Get provider info (JSON)
-#[no_mangle]
-pub extern "C" fn provider_info(ret_ptr: i32) -> i32 {
-    let info = serde_json::json!({
+/// Originator header value; CHATGPT_ORIGINATOR (e.g. `codex_cli`) overrides
+/// the default for accounts that restrict which clients may use Codex
+fn originator() -> String {
+    match env_get("CHATGPT_ORIGINATOR") {
+        Some(value) if is_valid_originator(&value) => value,
+        Some(value) => {
+            log_error(&format!(
+                "Ignoring invalid CHATGPT_ORIGINATOR '{}', using '{}'",
+                value, DEFAULT_ORIGINATOR
+            ));
+            DEFAULT_ORIGINATOR.to_string()
+        }
+        None => DEFAULT_ORIGINATOR.to_string(),
+    }
+}
+
+/// A header-safe token: 1-64 ASCII alphanumerics, `_`, `-` or `.`
+fn is_valid_originator(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 64
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// Endpoint, API mode and custom headers handed to tark (everything except
+/// the access token)
+fn request_description(
+    account_id: Option<&str>,
+    creds: Option<&OAuthCredentials>,
+) -> serde_json::Value {
+    let mut custom_headers = serde_json::Map::new();
+    custom_headers.insert("originator".to_string(), serde_json::json!(originator()));
+    if let Some(account_id_value) = account_id {
+        custom_headers.insert(
+            "ChatGPT-Account-Id".to_string(),
+            serde_json::json!(account_id_value),
+        );
+    }
+    let (organization, project) = creds.map(extract_org_and_project).unwrap_or_default();
+    if let Some(organization) = organization {
+        custom_headers.insert(
+            "OpenAI-Organization".to_string(),
+            serde_json::json!(organization),
+        );
+    }
+    if let Some(project) = project {
+        custom_headers.insert("OpenAI-Project".to_string(), serde_json::json!(project));
+    }
+    let streaming = streaming_enabled();
+    let accept = if streaming {
+        "text/event-stream"
+    } else {
+        "application/json"
+    };
+    custom_headers.insert("Accept".to_string(), serde_json::json!(accept));
+
+    serde_json::json!({
+        "api_mode": "openai_compat",
+        "endpoint": codex_endpoint(),
+        "api_shape": "responses",
+        "supports_streaming": streaming,
+        "supports_prompt_cache_key": true,
+        "custom_headers": custom_headers
+    })
+}
+
+/// Mask an identifier for display, keeping only its first 4 characters
+fn mask_id(id: &str) -> String {
+    let visible: String = id.chars().take(4).collect();
+    format!("{}***", visible)
+}
+
+// =============================================================================
+// Provider Plugin Interface - Auth Only
+// =============================================================================
+
+/// Called by tark when the plugin is loaded. Logs the plugin version and
+/// checks that the token endpoint answers; any HTTP status counts as
+/// reachable. The result is stored under `start_check` and shown by
+/// `provider_info`. Never fails, since the network may be down at start.
+/// Set `PLUGIN_START_CHECK=0` to skip the request.
+#[no_mangle]
+pub extern "C" fn provider_start() -> i32 {
+    log_info(&format!(
+        "chatgpt-oauth {} started",
+        env!("CARGO_PKG_VERSION")
+    ));
+    if env_get("PLUGIN_START_CHECK").as_deref() == Some("0") {
+        return 0;
+    }
+
+    let url = token_url();
+    let headers = vec![(
+        "Content-Type".to_string(),
+        "application/x-www-form-urlencoded".to_string(),
+    )];
+    let outcome = match http_post(&url, "", &headers) {
+        Some(raw) => classify_response(&raw),
+        None => HttpOutcome::TransportError,
+    };
+    let reachable = !matches!(outcome, HttpOutcome::TransportError);
+    if reachable {
+        log_info(&format!(
+            "Token endpoint {} is reachable ({})",
+            url, outcome
+        ));
+    } else {
+        log_error(&format!("Token endpoint {} is not reachable", url));
+    }
+    let check = serde_json::json!({
+        "checked_at": now_secs(),
+        "url": url,
+        "reachable": reachable,
+        "outcome": outcome.to_string()
+    });
+    storage_set(START_CHECK_KEY, &check.to_string());
+    0
+}
+
+/// Get provider info (JSON)
+#[no_mangle]
+pub extern "C" fn provider_info(ret_ptr: i32) -> i32 {
+    let info = serde_json::json!({
         "id": "chatgpt-oauth",
         "display_name": "ChatGPT (OAuth)",
         "description": "ChatGPT Pro/Plus via Codex API (auth-only plugin)",
         "requires_auth": true,
-        "provider": "openai"
+        "provider": "openai",
+        // Codex models accept `input_image` items; see README "Image inputs"
+        "supports_images": true,
+        "start_check": storage_get(START_CHECK_KEY)
+            .and_then(|raw| serde_json::from_str::<serde_json::Value>(&raw).ok()),
+        "credential_source": credential_source(),
+        "credentials_path": credentials_path()
     });
-    
+
     let json = info.to_string();
     unsafe {
         std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
@@ -580,7 +1560,7 @@ pub extern "C" fn provider_info(ret_ptr: i32) -> i32 {
 #[no_mangle]
 pub extern "C" fn provider_models(ret_ptr: i32) -> i32 {
     let models = serde_json::json!([]);
-    
+
     let json = models.to_string();
     unsafe {
         std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
@@ -592,11 +1572,7 @@ pub extern "C" fn provider_models(ret_ptr: i32) -> i32 {
 /// Extracts account_id from JWT and adds it to credentials
 /// Called by tark after OAuth flow completes
 #[no_mangle]
-pub extern "C" fn auth_process_tokens(
-    tokens_ptr: i32,
-    tokens_len: i32,
-    ret_ptr: i32,
-) -> i32 {
+pub extern "C" fn auth_process_tokens(tokens_ptr: i32, tokens_len: i32, ret_ptr: i32) -> i32 {
     // Read tokens JSON from WASM memory
     let tokens_json = unsafe {
         let slice = std::slice::from_raw_parts(tokens_ptr as *const u8, tokens_len as usize);
@@ -619,13 +1595,14 @@ pub extern "C" fn auth_process_tokens(
     };
 
     // Extract account_id from id_token or access_token JWT
-    let account_id = if let Some(id_token) = tokens.get("id_token").and_then(|t| t.as_str()) {
-        extract_account_id_from_token(id_token)
-    } else if let Some(access_token) = tokens.get("access_token").and_then(|t| t.as_str()) {
-        extract_account_id_from_token(access_token)
-    } else {
-        None
-    };
+    let account_id = account_id_from_tokens(
+        tokens.get("id_token").and_then(|t| t.as_str()),
+        tokens
+            .get("access_token")
+            .and_then(|t| t.as_str())
+            .unwrap_or(""),
+        None,
+    );
 
     // Add account_id if found
     if let Some(account_id_value) = account_id {
@@ -644,7 +1621,11 @@ pub extern "C" fn auth_process_tokens(
 
     let processed_bytes = processed_json.as_bytes();
     unsafe {
-        std::ptr::copy_nonoverlapping(processed_bytes.as_ptr(), ret_ptr as *mut u8, processed_bytes.len());
+        std::ptr::copy_nonoverlapping(
+            processed_bytes.as_ptr(),
+            ret_ptr as *mut u8,
+            processed_bytes.len(),
+        );
     }
     processed_bytes.len() as i32
 }
@@ -654,10 +1635,10 @@ pub extern "C" fn auth_process_tokens(
 #[no_mangle]
 pub extern "C" fn provider_auth_status() -> i32 {
     let state = load_state();
-    
+
     // Also check file if no state
     let has_creds = state.credentials.is_some() || load_credentials_from_file().is_some();
-    
+
     if has_creds {
         1 // Authenticated
     } else {
@@ -670,37 +1651,190 @@ pub extern "C" fn provider_auth_status() -> i32 {
 pub extern "C" fn provider_auth_init(creds_ptr: i32, creds_len: i32) -> i32 {
     let creds_slice =
         unsafe { std::slice::from_raw_parts(creds_ptr as *const u8, creds_len as usize) };
-    
+
     let creds_str = match std::str::from_utf8(creds_slice) {
         Ok(s) => s,
         Err(_) => return -1,
     };
-    
+
     let mut creds: OAuthCredentials = match serde_json::from_str(creds_str) {
         Ok(c) => c,
         Err(_) => return -2,
     };
-    
+
     // Extract account ID if not provided
     if creds.account_id.is_none() {
         creds.account_id = extract_account_id(&creds);
     }
-    
+
     log_info(&format!(
         "Loaded credentials: access_token_len={}, has_refresh={}, account_id={:?}",
         creds.access_token.len(),
         creds.refresh_token.is_some(),
         creds.account_id.as_ref().map(|s| &s[..s.len().min(8)])
     ));
-    
+
     let mut state = load_state();
     state.credentials = Some(creds);
     save_state(&state);
-    
+
     log_info("Provider initialized with ChatGPT OAuth credentials");
     0
 }
 
+/// Check and normalize a pasted credentials blob before tark saves it
+///
+/// Accepts JSON with `//` or `/* */` comments, snake_case or camelCase keys,
+/// tokens at the top level or nested under `tokens`/`credentials`, and the
+/// expiry as `expires_at` (seconds or milliseconds), `expiry_date` or
+/// relative `expires_in`. Unknown fields are dropped.
+///
+/// Returns the normalized `OAuthCredentials` JSON, or { "error" } with a
+/// negative length.
+#[no_mangle]
+pub extern "C" fn provider_normalize_credentials(
+    input_ptr: i32,
+    input_len: i32,
+    ret_ptr: i32,
+) -> i32 {
+    let input = unsafe { std::slice::from_raw_parts(input_ptr as *const u8, input_len as usize) };
+    let result = std::str::from_utf8(input)
+        .map_err(|_| "credentials are not valid UTF-8".to_string())
+        .and_then(|text| normalize_credentials(text, now_secs()));
+
+    let (json, ok) =
+        match result.and_then(|creds| serde_json::to_string(&creds).map_err(|e| e.to_string())) {
+            Ok(json) => (json, true),
+            Err(e) => (serde_json::json!({ "error": e }).to_string(), false),
+        };
+    unsafe {
+        std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
+    }
+    if ok {
+        json.len() as i32
+    } else {
+        -(json.len() as i32)
+    }
+}
+
+fn normalize_credentials(input: &str, now: u64) -> Result<OAuthCredentials, String> {
+    let value: serde_json::Value = serde_json::from_str(&strip_json_comments(input))
+        .map_err(|e| format!("credentials are not valid JSON: {}", e))?;
+    if !value.is_object() {
+        return Err("credentials must be a JSON object".to_string());
+    }
+    let creds = ["tokens", "credentials"]
+        .iter()
+        .filter_map(|key| lenient_get(&value, key))
+        .chain(std::iter::once(&value))
+        .find(|obj| lenient_get(obj, "access_token").is_some())
+        .ok_or("no access_token found (also looked under tokens and credentials)")?;
+
+    let text = |key: &str| {
+        lenient_get(creds, key)
+            .and_then(|v| v.as_str())
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(String::from)
+    };
+    let number = |key: &str| -> Result<Option<u64>, String> {
+        match lenient_get(creds, key) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(v) => v
+                .as_u64()
+                .or_else(|| v.as_f64().filter(|f| *f >= 0.0).map(|f| f as u64))
+                .or_else(|| v.as_str().and_then(|s| s.trim().parse().ok()))
+                .map(Some)
+                .ok_or_else(|| format!("{} must be a number of seconds", key)),
+        }
+    };
+
+    let access_token = text("access_token").ok_or("access_token is empty")?;
+    let expires_at = match (
+        number("expires_at")?,
+        number("expiry_date")?,
+        number("expires_in")?,
+    ) {
+        (Some(ts), _, _) | (None, Some(ts), _) => Some(timestamp_secs(ts)),
+        (None, None, Some(secs)) => Some(now + secs),
+        (None, None, None) => None,
+    };
+
+    let mut normalized = OAuthCredentials {
+        access_token,
+        refresh_token: text("refresh_token"),
+        id_token: text("id_token"),
+        expires_at,
+        account_id: text("account_id"),
+    };
+    if normalized.account_id.is_none() {
+        normalized.account_id = extract_account_id(&normalized);
+    }
+    Ok(normalized)
+}
+
+/// Look up `key` (snake_case) ignoring case, `_` and `-`, so `accessToken`
+/// and `Access-Token` match `access_token`
+fn lenient_get<'a>(value: &'a serde_json::Value, key: &str) -> Option<&'a serde_json::Value> {
+    let canonical = |k: &str| {
+        k.chars()
+            .filter(|c| *c != '_' && *c != '-')
+            .collect::<String>()
+            .to_ascii_lowercase()
+    };
+    let wanted = canonical(key);
+    value
+        .as_object()?
+        .iter()
+        .find(|(k, _)| canonical(k) == wanted)
+        .map(|(_, v)| v)
+}
+
+/// Remove `//` and `/* */` comments outside of strings
+fn strip_json_comments(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            match c {
+                '\\' => {
+                    if let Some(next) = chars.next() {
+                        out.push(next);
+                    }
+                }
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                out.push(c);
+            }
+            ('/', Some('/')) => {
+                while chars.peek().is_some_and(|&n| n != '\n') {
+                    chars.next();
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut prev = ' ';
+                for n in chars.by_ref() {
+                    if prev == '*' && n == '/' {
+                        break;
+                    }
+                    prev = n;
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
 /// Logout
 #[no_mangle]
 pub extern "C" fn provider_auth_logout() -> i32 {
@@ -710,13 +1844,122 @@ pub extern "C" fn provider_auth_logout() -> i32 {
     0
 }
 
+/// Reset all plugin state
+///
+/// Support-recommended recovery when stored state is corrupt or stale:
+/// deletes every storage key this plugin owns, including corrupt-state
+/// backups. Credentials files on disk are left untouched and are re-read on
+/// the next request.
+///
+/// Returns JSON: { "cleared": [keys] }
+#[no_mangle]
+pub extern "C" fn plugin_reset(ret_ptr: i32) -> i32 {
+    let cleared: Vec<String> = STORAGE_KEYS
+        .iter()
+        .map(|key| key.to_string())
+        .chain(storage_keys(CORRUPT_STATE_PREFIX))
+        .filter(|key| storage_delete(key))
+        .collect();
+    log_info(&format!("Plugin state reset (cleared {:?})", cleared));
+
+    let json = serde_json::json!({ "cleared": cleared }).to_string();
+    unsafe {
+        std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
+    }
+    json.len() as i32
+}
+
+/// Preload credentials
+///
+/// Called by tark after install/enable: loads the credentials file into state
+/// and refreshes the token if it is near expiry, so the first request does
+/// not pay for it.
+///
+/// Returns JSON: { "ready", "account_id_known", "error" }
+#[no_mangle]
+pub extern "C" fn provider_warm(ret_ptr: i32) -> i32 {
+    let summary = match get_valid_token() {
+        Ok((_, account_id)) => serde_json::json!({
+            "ready": true,
+            "account_id_known": account_id.is_some(),
+            "error": null
+        }),
+        Err(e) => serde_json::json!({
+            "ready": false,
+            "account_id_known": false,
+            "error": e
+        }),
+    };
+    log_info(&format!("Warm-up finished: {}", summary));
+
+    let json = summary.to_string();
+    unsafe {
+        std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
+    }
+    json.len() as i32
+}
+
+/// Re-derive the account id from the current tokens and store it, replacing
+/// a stale one (e.g. after the user joined another organization). The stored
+/// value is kept if the tokens carry no account id.
+///
+/// Returns JSON: { "account_id", "previous", "changed" } or { "error" }
+#[no_mangle]
+pub extern "C" fn provider_refresh_account_id(ret_ptr: i32) -> i32 {
+    let mut state = load_state();
+    if state.credentials.is_none() {
+        state.credentials = load_credentials_from_file();
+    }
+    let result = match state.credentials.as_mut() {
+        None => Err("No credentials stored".to_string()),
+        Some(creds) => {
+            let previous = creds.account_id.clone();
+            match account_id_from_tokens(creds.id_token.as_deref(), &creds.access_token, None) {
+                Some(account_id) => {
+                    let changed = previous.as_deref() != Some(account_id.as_str());
+                    creds.account_id = Some(account_id.clone());
+                    Ok(serde_json::json!({
+                        "account_id": account_id,
+                        "previous": previous,
+                        "changed": changed
+                    }))
+                }
+                None => Err("No account id found in the current tokens".to_string()),
+            }
+        }
+    };
+    if let Ok(summary) = &result {
+        save_state(&state);
+        log_info(&format!("Account id refreshed: {}", summary));
+    }
+
+    let (json, ok) = match result {
+        Ok(summary) => (summary.to_string(), true),
+        Err(e) => (serde_json::json!({ "error": e }).to_string(), false),
+    };
+    unsafe {
+        std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
+    }
+    if ok {
+        json.len() as i32
+    } else {
+        -(json.len() as i32)
+    }
+}
+
 /// Get auth credentials for tark's native provider
 ///
 /// This is the key function for auth-only plugins. Instead of implementing
 /// provider_chat(), we return credentials that tark uses to create a native
 /// OpenAI-compatible provider with the Codex endpoint.
 ///
-/// Returns JSON: { "access_token", "api_mode", "endpoint", "account_id" }
+/// Returns JSON: { "access_token", "api_mode", "endpoint", "api_shape",
+/// "supports_streaming", "supports_prompt_cache_key", "custom_headers" }
+///
+/// `api_shape: "responses"` means the endpoint speaks the OpenAI Responses API
+/// (not Chat Completions). When `supports_streaming` is true the host must send
+/// `stream: true` and parse the SSE event stream; reading the body as a single
+/// JSON document yields an empty response.
 #[no_mangle]
 pub extern "C" fn provider_auth_credentials(ret_ptr: i32) -> i32 {
     // Get valid token (refresh if needed)
@@ -734,34 +1977,223 @@ pub extern "C" fn provider_auth_credentials(ret_ptr: i32) -> i32 {
             return -(json.len() as i32); // Negative = error
         }
     };
-    
+
     log_debug(&format!(
         "Returning auth credentials: token_len={}, account_id={:?}",
         access_token.len(),
         account_id.as_ref().map(|s| &s[..s.len().min(8)])
     ));
-    
+
     // Return credentials for tark's OpenAI-compatible provider
-    let mut custom_headers = serde_json::Map::new();
-    custom_headers.insert("originator".to_string(), serde_json::json!("opencode"));
+    let stored = load_state().credentials;
+    let mut creds = request_description(account_id.as_deref(), stored.as_ref());
+    creds["access_token"] = serde_json::json!(access_token);
+
+    let json = creds.to_string();
+    unsafe {
+        std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
+    }
+    json.len() as i32
+}
+
+/// Preview the request tark would build from `provider_auth_credentials()`
+///
+/// Uses whatever credentials exist (stored or on disk) without refreshing,
+/// so it works even when the token is missing or expired. The account id
+/// header is masked and no token is included.
+///
+/// Returns JSON: { "endpoint", "api_mode", "api_shape", "supports_streaming",
+/// "custom_headers", "has_credentials", "token_expired" }
+#[no_mangle]
+pub extern "C" fn provider_preview_request(ret_ptr: i32) -> i32 {
+    let creds = load_state().credentials.or_else(load_credentials_from_file);
+    let account_id = creds
+        .as_ref()
+        .and_then(|c| c.account_id.clone().or_else(|| extract_account_id(c)));
+
+    let mut preview = request_description(account_id.as_deref(), creds.as_ref());
     if let Some(account_id_value) = &account_id {
-        custom_headers.insert("ChatGPT-Account-Id".to_string(), serde_json::json!(account_id_value));
+        preview["custom_headers"]["ChatGPT-Account-Id"] =
+            serde_json::json!(mask_id(account_id_value));
     }
-    
-    let creds = serde_json::json!({
-        "access_token": access_token,
-        "api_mode": "openai_compat",
-        "endpoint": CODEX_API_ENDPOINT,
-        "custom_headers": custom_headers
+    preview["has_credentials"] = serde_json::json!(creds.is_some());
+    preview["token_expired"] = serde_json::json!(creds.as_ref().map(is_expired));
+
+    let json = preview.to_string();
+    unsafe {
+        std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
+    }
+    json.len() as i32
+}
+
+/// Check that the fs capability grants the credentials file
+///
+/// Attempts to read the resolved `credentials_path()` and explains failures
+/// so `tark plugin doctor` can tell the user which path to grant.
+///
+/// Returns JSON: { "path", "readable", "reason" }
+#[no_mangle]
+pub extern "C" fn provider_check_fs(ret_ptr: i32) -> i32 {
+    let path = credentials_path();
+    let (readable, reason) = match fs_read_result(&path) {
+        Ok(_) => (true, None),
+        Err(0) => (false, Some("file is empty")),
+        Err(-1) => (false, Some("invalid path")),
+        Err(-2) => (
+            false,
+            Some("permission denied: add this path to capabilities.fs_read"),
+        ),
+        Err(FS_NOT_IN_ALLOWLIST) => (false, Some("blocked by PLUGIN_FS_ALLOWLIST")),
+        Err(_) => (false, Some("not found or unreadable")),
+    };
+
+    let result = serde_json::json!({
+        "path": path,
+        "readable": readable,
+        "reason": reason
     });
-    
-    let json = creds.to_string();
+    let json = result.to_string();
     unsafe {
         std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
     }
     json.len() as i32
 }
 
+/// Tell the plugin that Codex rejected a request made with our token
+///
+/// Input JSON: the host's `{status, headers, body}` for the failed call.
+/// A plain 401 (`invalid_token`) marks the stored token expired, so the
+/// next `provider_auth_credentials()` refreshes it. `insufficient_scope`
+/// cannot be fixed by a refresh and asks for re-authentication instead.
+///
+/// Returns JSON: { "action": "refresh" | "reauth" | "none", "error"? }
+#[no_mangle]
+pub extern "C" fn provider_auth_rejected(req_ptr: i32, req_len: i32, ret_ptr: i32) -> i32 {
+    let req_slice = unsafe { std::slice::from_raw_parts(req_ptr as *const u8, req_len as usize) };
+    let raw = String::from_utf8_lossy(req_slice);
+    let result = match classify_response(&raw) {
+        HttpOutcome::InsufficientScope(status) => {
+            log_error(&format!(
+                "Codex answered HTTP {} insufficient_scope",
+                status
+            ));
+            serde_json::json!({
+                "action": "reauth",
+                "error": "ChatGPT token lacks a required scope (insufficient_scope). \
+                          Run 'tark auth chatgpt' to re-authenticate with the required scopes."
+            })
+        }
+        HttpOutcome::Unauthorized => {
+            let mut state = load_state();
+            if let Some(creds) = state.credentials.as_mut() {
+                creds.expires_at = Some(0);
+                save_state(&state);
+            }
+            log_info("Codex rejected the access token, will refresh on next request");
+            serde_json::json!({ "action": "refresh" })
+        }
+        _ => serde_json::json!({ "action": "none" }),
+    };
+
+    let json = result.to_string();
+    unsafe {
+        std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
+    }
+    json.len() as i32
+}
+
+/// Shape a tool result as a Codex Responses API `input` item
+///
+/// Input JSON: { "name", "call_id", "output" }. `output` may be a string or
+/// any JSON value (serialized to a string, which is what Codex expects).
+/// `name` is accepted for symmetry with the tool call but is not part of
+/// the item.
+///
+/// Returns JSON: { "type": "function_call_output", "call_id", "output" }
+#[no_mangle]
+pub extern "C" fn provider_format_tool_result(req_ptr: i32, req_len: i32, ret_ptr: i32) -> i32 {
+    let req_slice = unsafe { std::slice::from_raw_parts(req_ptr as *const u8, req_len as usize) };
+    let result = serde_json::from_slice::<serde_json::Value>(req_slice)
+        .map_err(|e| format!("invalid request: {}", e))
+        .and_then(|req| format_tool_result(&req));
+
+    let (json, ok) = match result {
+        Ok(item) => (item.to_string(), true),
+        Err(e) => (serde_json::json!({ "error": e }).to_string(), false),
+    };
+    unsafe {
+        std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
+    }
+    if ok {
+        json.len() as i32
+    } else {
+        -(json.len() as i32)
+    }
+}
+
+fn format_tool_result(req: &serde_json::Value) -> Result<serde_json::Value, String> {
+    let call_id = req
+        .get("call_id")
+        .and_then(|v| v.as_str())
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| "call_id is required".to_string())?;
+    let output = match req.get("output") {
+        Some(serde_json::Value::String(text)) => text.clone(),
+        Some(serde_json::Value::Null) | None => String::new(),
+        Some(other) => other.to_string(),
+    };
+    Ok(serde_json::json!({
+        "type": "function_call_output",
+        "call_id": call_id,
+        "output": output
+    }))
+}
+
+/// Per-request Responses API parameters for a conversation
+///
+/// Input JSON: { "conversation_id" }
+///
+/// Returns JSON: { "prompt_cache_key" }. The key is stable for a
+/// conversation, so repeated requests that replay the same prefix (system
+/// prompt, tools, earlier turns) hit the Codex prompt cache. It is a hash,
+/// so the conversation id itself is not sent to OpenAI.
+#[no_mangle]
+pub extern "C" fn provider_params(req_ptr: i32, req_len: i32, ret_ptr: i32) -> i32 {
+    let req_slice = unsafe { std::slice::from_raw_parts(req_ptr as *const u8, req_len as usize) };
+    let result = serde_json::from_slice::<serde_json::Value>(req_slice)
+        .map_err(|e| format!("invalid request: {}", e))
+        .and_then(|req| {
+            req.get("conversation_id")
+                .and_then(|v| v.as_str())
+                .filter(|id| !id.is_empty())
+                .map(|id| serde_json::json!({ "prompt_cache_key": prompt_cache_key(id) }))
+                .ok_or_else(|| "conversation_id is required".to_string())
+        });
+
+    let (json, ok) = match result {
+        Ok(params) => (params.to_string(), true),
+        Err(e) => (serde_json::json!({ "error": e }).to_string(), false),
+    };
+    unsafe {
+        std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
+    }
+    if ok {
+        json.len() as i32
+    } else {
+        -(json.len() as i32)
+    }
+}
+
+/// `tark-` plus a 64-bit FNV-1a hash of the conversation id
+fn prompt_cache_key(conversation_id: &str) -> String {
+    let hash = conversation_id
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    format!("tark-{:016x}", hash)
+}
+
 // =============================================================================
 // Legacy Interface (backwards compatibility)
 // =============================================================================
@@ -812,9 +2244,382 @@ pub extern "C" fn init_with_credentials(creds_ptr: i32, creds_len: i32) -> i32 {
 
 #[no_mangle]
 pub extern "C" fn get_endpoint(ret_ptr: i32) -> i32 {
-    let endpoint = CODEX_API_ENDPOINT;
+    let endpoint = codex_endpoint();
     unsafe {
         std::ptr::copy_nonoverlapping(endpoint.as_ptr(), ret_ptr as *mut u8, endpoint.len());
     }
     endpoint.len() as i32
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn creds_expiring_at(expires_at: Option<u64>) -> OAuthCredentials {
+        OAuthCredentials {
+            access_token: "token".to_string(),
+            refresh_token: None,
+            id_token: None,
+            expires_at,
+            account_id: None,
+        }
+    }
+
+    #[test]
+    fn expires_at_is_compared_in_seconds() {
+        let now = now_secs();
+        assert!(is_expired(&creds_expiring_at(Some(now - 10))));
+        assert!(!is_expired(&creds_expiring_at(Some(now + 3600))));
+    }
+
+    #[test]
+    fn expires_at_within_refresh_buffer_is_expired() {
+        let now = now_secs();
+        assert!(is_expired(&creds_expiring_at(Some(now + 200))));
+        assert!(!is_expired(&creds_expiring_at(Some(now + 400))));
+    }
+
+    #[test]
+    fn expires_at_in_milliseconds_is_read_as_milliseconds() {
+        let past_ms = (now_secs() - 3600) * 1000;
+        assert!(is_expired(&creds_expiring_at(Some(past_ms))));
+        let future_ms = (now_secs() + 3600) * 1000;
+        assert!(!is_expired(&creds_expiring_at(Some(future_ms))));
+    }
+
+    #[test]
+    fn missing_expires_at_is_not_expired() {
+        assert!(!is_expired(&creds_expiring_at(None)));
+    }
+
+    #[test]
+    fn salvage_state_keeps_tokens_despite_bad_fields() {
+        let raw = r#"{"credentials":{"access_token":"a","refresh_token":"r","expires_at":"soon"}}"#;
+        assert!(serde_json::from_str::<PluginState>(raw).is_err());
+
+        let creds = salvage_state(raw)
+            .and_then(|s| s.credentials)
+            .expect("credentials");
+        assert_eq!(creds.access_token, "a");
+        assert_eq!(creds.refresh_token.as_deref(), Some("r"));
+        assert_eq!(creds.expires_at, None);
+
+        assert!(salvage_state(r#"{"credentials":null}"#).is_none());
+    }
+
+    #[test]
+    fn originator_accepts_simple_tokens_only() {
+        assert!(is_valid_originator("codex_cli"));
+        assert!(is_valid_originator("opencode"));
+        assert!(!is_valid_originator(""));
+        assert!(!is_valid_originator("codex cli"));
+        assert!(!is_valid_originator("x\r\nInjected: 1"));
+    }
+
+    #[test]
+    fn classify_response_maps_statuses() {
+        let wrap = |status: u16| format!(r#"{{"status":{},"headers":[],"body":"b"}}"#, status);
+        assert_eq!(
+            classify_response(&wrap(200)),
+            HttpOutcome::Success("b".into())
+        );
+        assert_eq!(classify_response(&wrap(401)), HttpOutcome::Unauthorized);
+        assert_eq!(classify_response(&wrap(503)), HttpOutcome::Retryable(503));
+        assert_eq!(
+            classify_response(&wrap(400)),
+            HttpOutcome::ClientError {
+                status: 400,
+                body: "b".into()
+            }
+        );
+        assert_eq!(classify_response(&wrap(0)), HttpOutcome::TransportError);
+        assert_eq!(
+            classify_response(r#"{"access_token":"t"}"#),
+            HttpOutcome::Success(r#"{"access_token":"t"}"#.into())
+        );
+    }
+
+    #[test]
+    fn classify_response_reads_retry_after() {
+        let pairs = r#"{"status":429,"headers":[["Retry-After","7"]],"body":""}"#;
+        assert_eq!(
+            classify_response(pairs),
+            HttpOutcome::RateLimited {
+                retry_after: Some(7)
+            }
+        );
+        let object = r#"{"status":429,"headers":{"retry-after":"3"},"body":""}"#;
+        assert_eq!(
+            classify_response(object),
+            HttpOutcome::RateLimited {
+                retry_after: Some(3)
+            }
+        );
+    }
+
+    #[test]
+    fn tool_result_is_a_function_call_output_item() {
+        let req = serde_json::json!({ "name": "read", "call_id": "call_1", "output": "ok" });
+        assert_eq!(
+            format_tool_result(&req).unwrap(),
+            serde_json::json!({
+                "type": "function_call_output",
+                "call_id": "call_1",
+                "output": "ok"
+            })
+        );
+        let structured = serde_json::json!({ "call_id": "call_2", "output": { "lines": 3 } });
+        assert_eq!(
+            format_tool_result(&structured).unwrap()["output"],
+            r#"{"lines":3}"#
+        );
+        assert!(format_tool_result(&serde_json::json!({ "output": "x" })).is_err());
+    }
+
+    #[test]
+    fn refresh_errors_distinguish_invalid_grant_from_non_json() {
+        #[derive(Debug, Deserialize)]
+        struct Token {
+            #[allow(dead_code)]
+            access_token: String,
+        }
+        let revoked = r#"{"status":400,"headers":[],"body":"{\"error\":\"invalid_grant\",\"error_description\":\"revoked\"}"}"#;
+        assert!(matches!(
+            parse_token_response::<Token>(revoked),
+            Err(RefreshError::InvalidGrant(d)) if d == "revoked"
+        ));
+        let gateway = r#"{"status":502,"headers":[["Content-Type","text/html"]],"body":"<html>Bad Gateway</html>"}"#;
+        match parse_token_response::<Token>(gateway) {
+            Err(RefreshError::NonJson {
+                status,
+                content_type,
+                body,
+            }) => {
+                assert_eq!(status, 502);
+                assert_eq!(content_type.as_deref(), Some("text/html"));
+                assert_eq!(body, "<html>Bad Gateway</html>");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let ok = r#"{"status":200,"headers":[],"body":"{\"access_token\":\"t\"}"}"#;
+        assert!(parse_token_response::<Token>(ok).is_ok());
+    }
+
+    #[test]
+    fn refresh_errors_keep_openai_error_message_whole() {
+        #[derive(Debug, Deserialize)]
+        struct Token {
+            #[allow(dead_code)]
+            access_token: String,
+        }
+        let reused = r#"{"status":401,"headers":[],"body":"{\"error\":{\"message\":\"Your refresh token has already been used to generate a new access token. Please try signing in again.\",\"type\":\"invalid_request_error\",\"param\":null,\"code\":\"refresh_token_reused\"}}"}"#;
+        match parse_token_response::<Token>(reused) {
+            Err(RefreshError::OAuth { error, description }) => {
+                assert_eq!(error, "refresh_token_reused");
+                assert_eq!(
+                    description.as_deref(),
+                    Some("Your refresh token has already been used to generate a new access token. Please try signing in again.")
+                );
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let no_code = r#"{"status":400,"headers":[],"body":"{\"error\":{\"message\":\"Invalid client.\",\"type\":\"invalid_request_error\",\"param\":null,\"code\":null}}"}"#;
+        match parse_token_response::<Token>(no_code) {
+            Err(RefreshError::OAuth { error, .. }) => assert_eq!(error, "invalid_request_error"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn body_excerpt_cuts_on_char_boundary() {
+        assert_eq!(body_excerpt("héllo", 2), "h");
+        assert_eq!(body_excerpt("short", 500), "short");
+    }
+
+    #[test]
+    fn account_id_falls_back_to_stored_value() {
+        assert_eq!(
+            account_id_from_tokens(None, "not-a-jwt", Some("acc_1")),
+            Some("acc_1".to_string())
+        );
+        assert_eq!(account_id_from_tokens(None, "not-a-jwt", None), None);
+    }
+
+    #[test]
+    fn fs_allowlist_matches_files_and_directories() {
+        let allowlist = vec![
+            "/home/u/.config/tark/chatgpt_oauth.json".to_string(),
+            "/run/secrets/".to_string(),
+        ];
+        assert!(path_in_allowlist(
+            "/home/u/.config/tark/chatgpt_oauth.json",
+            &allowlist
+        ));
+        assert!(path_in_allowlist("/run/secrets/chatgpt.json", &allowlist));
+        assert!(!path_in_allowlist("/home/u/.ssh/id_rsa", &allowlist));
+        assert!(!path_in_allowlist(
+            "/run/secrets/../../etc/passwd",
+            &allowlist
+        ));
+    }
+
+    #[test]
+    fn org_header_follows_the_selected_workspace() {
+        let claims = serde_json::json!({
+            "https://api.openai.com/auth": {
+                "organizations": [
+                    { "id": "org-personal", "is_default": true },
+                    { "id": "org-team" }
+                ]
+            }
+        });
+        assert_eq!(
+            organization_from_claims(&claims).as_deref(),
+            Some("org-personal")
+        );
+
+        MOCK_ENV.with(|env| {
+            env.borrow_mut()
+                .insert("CHATGPT_ORG_ID".into(), "org-team".into())
+        });
+        assert_eq!(
+            organization_from_claims(&claims).as_deref(),
+            Some("org-team")
+        );
+        MOCK_ENV.with(|env| env.borrow_mut().clear());
+    }
+
+    #[test]
+    fn refreshed_token_needs_codex_model_scope() {
+        let scopes = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(missing_codex_scopes(&scopes(&["openid", "model.request"])).is_empty());
+        assert_eq!(
+            missing_codex_scopes(&scopes(&["openid", "offline_access"])),
+            vec!["model.request"]
+        );
+    }
+
+    #[test]
+    fn insufficient_scope_is_not_a_plain_unauthorized() {
+        let challenge = r#"{"status":401,"headers":[["WWW-Authenticate","Bearer realm=\"codex\", error=\"insufficient_scope\", scope=\"model.request\""]],"body":""}"#;
+        assert_eq!(
+            classify_response(challenge),
+            HttpOutcome::InsufficientScope(401)
+        );
+        let expired = r#"{"status":401,"headers":[["WWW-Authenticate","Bearer error=\"invalid_token\""]],"body":""}"#;
+        assert_eq!(classify_response(expired), HttpOutcome::Unauthorized);
+        let body = r#"{"status":403,"headers":[],"body":"{\"error\":\"insufficient_scope\"}"}"#;
+        assert!(matches!(
+            parse_token_response::<serde_json::Value>(body),
+            Err(RefreshError::InsufficientScope(_))
+        ));
+    }
+
+    #[test]
+    fn urlencoding_leaves_only_unreserved_chars() {
+        let unreserved = "ABCXYZabcxyz0189-_.~";
+        assert_eq!(urlencoding_encode(unreserved), unreserved);
+        assert_eq!(urlencoding_encode("+/="), "%2B%2F%3D");
+        assert_eq!(urlencoding_encode("a b"), "a%20b");
+        assert_eq!(urlencoding_encode("é"), "%C3%A9");
+        assert_eq!(urlencoding_encode("🔑"), "%F0%9F%94%91");
+        for byte in 0u8..0x80 {
+            let c = byte as char;
+            let encoded = urlencoding_encode(&c.to_string());
+            if c.is_ascii_alphanumeric() || "-_.~".contains(c) {
+                assert_eq!(encoded, c.to_string());
+            } else {
+                assert_eq!(encoded, format!("%{:02X}", byte));
+            }
+        }
+    }
+
+    #[test]
+    fn endpoint_env_wins_over_file() {
+        let config: EndpointConfig =
+            serde_json::from_str(r#"{"token_url":"https://proxy.example/token"}"#).unwrap();
+        let env = Some("http://localhost:9000/token".to_string());
+        assert_eq!(
+            pick_endpoint(env, config.token_url.clone(), TOKEN_URL),
+            "http://localhost:9000/token"
+        );
+        assert_eq!(
+            pick_endpoint(Some(" ".into()), config.token_url, TOKEN_URL),
+            "https://proxy.example/token"
+        );
+        assert_eq!(
+            pick_endpoint(None, config.api_endpoint, CODEX_API_ENDPOINT),
+            CODEX_API_ENDPOINT
+        );
+    }
+
+    #[test]
+    fn prompt_cache_key_is_stable_per_conversation() {
+        let key = prompt_cache_key("conv-1");
+        assert_eq!(key, prompt_cache_key("conv-1"));
+        assert_ne!(key, prompt_cache_key("conv-2"));
+        assert!(key.starts_with("tark-") && key.len() == 21);
+        assert!(!key.contains("conv"));
+    }
+
+    #[test]
+    fn pasted_credentials_are_normalized() {
+        let blob = r#"{
+            // exported from another tool
+            "tokens": { "accessToken": "at", "Refresh-Token": "rt", "expiresIn": 3600 },
+            "url": "https://example.com/*not a comment*/"
+        }"#;
+        let creds = normalize_credentials(blob, 1_000).unwrap();
+        assert_eq!(creds.access_token, "at");
+        assert_eq!(creds.refresh_token.as_deref(), Some("rt"));
+        assert_eq!(creds.expires_at, Some(4_600));
+
+        let ms = r#"{"access_token":"at","expiry_date":1700000000000}"#;
+        assert_eq!(
+            normalize_credentials(ms, 0).unwrap().expires_at,
+            Some(1_700_000_000)
+        );
+        assert!(normalize_credentials(r#"{"refresh_token":"rt"}"#, 0).is_err());
+        assert!(normalize_credentials(r#"{"access_token":"at","expires_at":"soon"}"#, 0).is_err());
+    }
+
+    #[test]
+    fn expired_token_is_refreshed_against_mock_endpoint() {
+        let mock_url = "http://127.0.0.1:8799/oauth/token";
+        MOCK_ENV.with(|env| {
+            env.borrow_mut()
+                .insert("CHATGPT_TOKEN_URL".into(), mock_url.into())
+        });
+        save_state(&PluginState {
+            credentials: Some(OAuthCredentials {
+                access_token: "old-access".to_string(),
+                refresh_token: Some("old-refresh".to_string()),
+                id_token: None,
+                expires_at: Some(1),
+                account_id: Some("acc_1".to_string()),
+            }),
+        });
+        MOCK_HTTP.with(|http| {
+            *http.borrow_mut() = Some(Box::new(move |url, body| {
+                assert_eq!(url, mock_url);
+                assert!(body.contains("refresh_token=old-refresh"));
+                serde_json::json!({
+                    "status": 200,
+                    "headers": [],
+                    "body": r#"{"access_token":"new-access","refresh_token":"new-refresh","expires_in":3600}"#
+                })
+                .to_string()
+            }))
+        });
+
+        let (token, account_id) = get_valid_token().expect("refresh succeeds");
+        assert_eq!(token, "new-access");
+        assert_eq!(account_id.as_deref(), Some("acc_1"));
+
+        let stored = load_state().credentials.expect("stored credentials");
+        assert_eq!(stored.access_token, "new-access");
+        assert_eq!(stored.refresh_token.as_deref(), Some("new-refresh"));
+        assert!(!is_expired(&stored));
+    }
+}
@@ -7,7 +7,7 @@ echo "Building discord channel WASM plugin..."
 
 rustup target add wasm32-wasip1 2>/dev/null || true
 
-cargo build --target wasm32-wasip1 --release
+cargo build --target wasm32-wasip1 --release "$@"
 
 mkdir -p dist
 cp target/wasm32-wasip1/release/tark_plugin_discord_channel.wasm dist/plugin.wasm
@@ -6,16 +6,88 @@
 use ed25519_dalek::{Signature, VerifyingKey};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const DISCORD_GATEWAY_URL: &str = "wss://gateway.discord.gg/?v=10&encoding=json";
 const DISCORD_INTENTS_DM_ONLY: u64 = 1 | 4096 | 32768; // GUILDS + DIRECT_MESSAGES + MESSAGE_CONTENT
+const DISCORD_INTENT_GUILD_MESSAGES: u64 = 512;
+const DISCORD_INTENT_MESSAGE_CONTENT: u64 = 32768;
+/// Gateway intents accepted by name in the `intents` config
+const DISCORD_INTENT_NAMES: &[(&str, u64)] = &[
+    ("GUILDS", 1 << 0),
+    ("GUILD_MEMBERS", 1 << 1),
+    ("GUILD_MODERATION", 1 << 2),
+    ("GUILD_EXPRESSIONS", 1 << 3),
+    ("GUILD_INTEGRATIONS", 1 << 4),
+    ("GUILD_WEBHOOKS", 1 << 5),
+    ("GUILD_INVITES", 1 << 6),
+    ("GUILD_VOICE_STATES", 1 << 7),
+    ("GUILD_PRESENCES", 1 << 8),
+    ("GUILD_MESSAGES", 1 << 9),
+    ("GUILD_MESSAGE_REACTIONS", 1 << 10),
+    ("GUILD_MESSAGE_TYPING", 1 << 11),
+    ("DIRECT_MESSAGES", 1 << 12),
+    ("DIRECT_MESSAGE_REACTIONS", 1 << 13),
+    ("DIRECT_MESSAGE_TYPING", 1 << 14),
+    ("MESSAGE_CONTENT", 1 << 15),
+    ("GUILD_SCHEDULED_EVENTS", 1 << 16),
+    ("AUTO_MODERATION_CONFIGURATION", 1 << 20),
+    ("AUTO_MODERATION_EXECUTION", 1 << 21),
+    ("GUILD_MESSAGE_POLLS", 1 << 24),
+    ("DIRECT_MESSAGE_POLLS", 1 << 25),
+];
+const DEFAULT_MAX_MISSED_HEARTBEAT_ACKS: u32 = 1;
+/// Local IDENTIFY rate limit: at most IDENTIFY_LIMIT per IDENTIFY_WINDOW_SECS
+const IDENTIFY_LIMIT: usize = 5;
+const IDENTIFY_WINDOW_SECS: u64 = 5;
+/// After a non-resumable INVALID_SESSION Discord wants a wait of 1 to 5
+/// seconds before the fresh IDENTIFY
+const INVALID_SESSION_MIN_WAIT_SECS: u64 = 1;
+const INVALID_SESSION_MAX_WAIT_SECS: u64 = 5;
+/// A user with an unanswered message is released after this long even if no reply was sent.
+const USER_IN_FLIGHT_TTL_SECS: u64 = 120;
+/// Interaction tokens can be used for follow-ups for 15 minutes.
+const INTERACTION_TOKEN_TTL_SECS: u64 = 15 * 60;
+/// Interaction requests signed further than this from our clock are rejected
+const SIGNATURE_TOLERANCE_SECS: u64 = 300;
+/// Host retries of a send with the same nonce within this window reuse the
+/// first message instead of posting again.
+const SEND_NONCE_TTL_SECS: u64 = 10 * 60;
+/// Fixed storage keys owned by this plugin (cleared by `plugin_reset`)
+const STORAGE_KEYS: &[&str] = &[
+    "discord_application_id",
+    "discord_public_key",
+    "discord_bot_token",
+    "discord_oauth_tokens",
+    "discord_private_mode",
+    "discord_access_control",
+    "discord_gateway_intents",
+    GATEWAY_SESSION_KEY,
+    STATS_KEY,
+];
+/// Storage key of the lifetime message counters
+const STATS_KEY: &str = "discord_stats";
+/// Seconds between stats writes unless `DISCORD_STATS_PERSIST_SECS` is set
+const DEFAULT_STATS_PERSIST_SECS: u64 = 30;
+/// Storage key of the gateway session kept for RESUME across restarts
+const GATEWAY_SESSION_KEY: &str = "discord_gateway_session";
+/// Prefixes of per-conversation storage keys (swept by `plugin_reset`)
+const CONVERSATION_KEY_PREFIXES: &[&str] = &[
+    "discord_interaction_token:",
+    "discord_tool_msg:",
+    "discord_session_header:",
+    "discord_conversation:",
+    "discord_send_nonce:",
+    "discord_dm_channel:",
+];
 
 // =============================================================================
 // Host Function Imports (provided by tark)
 // =============================================================================
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:storage")]
 extern "C" {
     #[link_name = "get"]
@@ -26,8 +98,12 @@ extern "C" {
 
     #[link_name = "delete"]
     fn storage_delete_raw(key_ptr: i32, key_len: i32) -> i32;
+
+    #[link_name = "keys"]
+    fn storage_keys_raw(prefix_ptr: i32, prefix_len: i32, ret_ptr: i32) -> i32;
 }
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:http")]
 extern "C" {
     #[link_name = "post"]
@@ -42,6 +118,7 @@ extern "C" {
     ) -> i32;
 }
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:ws")]
 extern "C" {
     #[link_name = "connect"]
@@ -57,17 +134,13 @@ extern "C" {
     fn ws_send_raw(handle: i64, data_ptr: i32, data_len: i32, ret_ptr: i32) -> i32;
 
     #[link_name = "recv"]
-    fn ws_recv_raw(
-        handle: i64,
-        timeout_ms: i64,
-        max_bytes: i64,
-        ret_ptr: i32,
-    ) -> i32;
+    fn ws_recv_raw(handle: i64, timeout_ms: i64, max_bytes: i64, ret_ptr: i32) -> i32;
 
     #[link_name = "close"]
     fn ws_close_raw(handle: i64, ret_ptr: i32) -> i32;
 }
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:log")]
 extern "C" {
     #[link_name = "info"]
@@ -76,17 +149,135 @@ extern "C" {
     fn log_error_raw(msg_ptr: i32, msg_len: i32);
 }
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:env")]
 extern "C" {
     #[link_name = "get"]
     fn env_get_raw(name_ptr: i32, name_len: i32, ret_ptr: i32) -> i32;
 }
 
+#[cfg(not(test))]
+#[link(wasm_import_module = "tark:fs")]
+extern "C" {
+    #[link_name = "read"]
+    fn fs_read_raw(path_ptr: i32, path_len: i32, ret_ptr: i32) -> i32;
+}
+
+// =============================================================================
+// Host Function Stubs (native unit tests)
+// =============================================================================
+
+/// Stand-ins for the tark host imports so the plugin logic can be unit tested
+/// natively. Every call reports "unavailable" without touching `ret_ptr`.
+#[cfg(test)]
+mod host_stubs {
+    pub unsafe fn storage_get_raw(_key_ptr: i32, _key_len: i32, _ret_ptr: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn storage_set_raw(
+        _key_ptr: i32,
+        _key_len: i32,
+        _val_ptr: i32,
+        _val_len: i32,
+    ) -> i32 {
+        -1
+    }
+
+    pub unsafe fn storage_delete_raw(_key_ptr: i32, _key_len: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn storage_keys_raw(_prefix_ptr: i32, _prefix_len: i32, _ret_ptr: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn http_post_raw(
+        _url_ptr: i32,
+        _url_len: i32,
+        _body_ptr: i32,
+        _body_len: i32,
+        _headers_ptr: i32,
+        _headers_len: i32,
+        _ret_ptr: i32,
+    ) -> i32 {
+        -1
+    }
+
+    pub unsafe fn ws_connect_raw(
+        _url_ptr: i32,
+        _url_len: i32,
+        _headers_ptr: i32,
+        _headers_len: i32,
+        _ret_ptr: i32,
+    ) -> i32 {
+        -1
+    }
+
+    pub unsafe fn ws_send_raw(_handle: i64, _data_ptr: i32, _data_len: i32, _ret_ptr: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn ws_recv_raw(
+        _handle: i64,
+        _timeout_ms: i64,
+        _max_bytes: i64,
+        _ret_ptr: i32,
+    ) -> i32 {
+        -1
+    }
+
+    pub unsafe fn ws_close_raw(_handle: i64, _ret_ptr: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn log_info_raw(_msg_ptr: i32, _msg_len: i32) {}
+
+    pub unsafe fn log_error_raw(_msg_ptr: i32, _msg_len: i32) {}
+
+    pub unsafe fn env_get_raw(_name_ptr: i32, _name_len: i32, _ret_ptr: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn fs_read_raw(_path_ptr: i32, _path_len: i32, _ret_ptr: i32) -> i32 {
+        -1
+    }
+
+    thread_local! {
+        /// `(url, body)` of every `http_post` made on this thread
+        pub static HTTP_POSTS: std::cell::RefCell<Vec<(String, String)>> =
+            const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    /// Records the request and answers like Discord creating message "1"
+    pub fn mock_http_post(
+        url: &str,
+        body: &str,
+        _headers: &[(String, String)],
+    ) -> Option<super::HttpResponse> {
+        HTTP_POSTS.with(|posts| posts.borrow_mut().push((url.to_string(), body.to_string())));
+        Some(super::HttpResponse {
+            status: 200,
+            headers: Vec::new(),
+            body: r#"{"id":"1"}"#.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+use host_stubs::*;
+
 // =============================================================================
 // Memory Management
 // =============================================================================
 
-static mut RETURN_BUFFER: [u8; 262144] = [0u8; 262144];
+/// Capacity of RETURN_BUFFER, chosen with the `buffer-*` Cargo features
+#[cfg(feature = "buffer-512k")]
+const RETURN_BUFFER_SIZE: usize = 512 * 1024;
+#[cfg(not(feature = "buffer-512k"))]
+const RETURN_BUFFER_SIZE: usize = 256 * 1024;
+
+static mut RETURN_BUFFER: [u8; RETURN_BUFFER_SIZE] = [0u8; RETURN_BUFFER_SIZE];
 static mut ENV_BUFFER: [u8; 512] = [0u8; 512];
 
 fn return_buffer_ptr() -> i32 {
@@ -98,7 +289,8 @@ fn env_buffer_ptr() -> i32 {
 }
 
 unsafe fn return_buffer_bytes(len: i32) -> &'static [u8] {
-    std::slice::from_raw_parts(std::ptr::addr_of!(RETURN_BUFFER).cast::<u8>(), len as usize)
+    let len = (len as usize).min(RETURN_BUFFER_SIZE);
+    std::slice::from_raw_parts(std::ptr::addr_of!(RETURN_BUFFER).cast::<u8>(), len)
 }
 
 unsafe fn env_buffer_bytes(len: i32) -> &'static [u8] {
@@ -124,7 +316,9 @@ pub extern "C" fn dealloc(ptr: i32, len: i32) {
 #[derive(Debug, Deserialize)]
 struct WebhookRequest {
     method: String,
+    #[allow(dead_code)]
     path: String,
+    #[allow(dead_code)]
     query: Option<String>,
     headers: Vec<(String, String)>,
     body: String,
@@ -152,6 +346,12 @@ struct InteractionToken {
     created_at: u64,
 }
 
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ConversationActivity {
+    last_activity: u64,
+    message_count: u64,
+}
+
 #[derive(Debug, Deserialize)]
 struct OAuthTokens {
     access_token: String,
@@ -166,11 +366,96 @@ struct DiscordConfig {
     application_id: String,
     public_key: String,
     bot_token: Option<String>,
+    private_mode: PrivateMode,
+    access: AccessControl,
+    /// Config `intents`; `None` derives them from `private_mode`
+    intents: Option<u64>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Who may use the bot (config `allowed_roles` / `allowed_users`). With
+/// both lists empty everyone may; otherwise a user needs to be listed or to
+/// hold one of the roles. DMs carry no roles, so only `allowed_users` admits
+/// them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct AccessControl {
+    allowed_roles: Vec<String>,
+    allowed_users: Vec<String>,
+}
+
+impl AccessControl {
+    fn allows(&self, user_id: &str, roles: &[String]) -> bool {
+        if self.allowed_roles.is_empty() && self.allowed_users.is_empty() {
+            return true;
+        }
+        self.allowed_users.iter().any(|u| u == user_id)
+            || roles.iter().any(|r| self.allowed_roles.contains(r))
+    }
+
+    fn to_json(&self) -> Value {
+        serde_json::json!({
+            "allowed_roles": self.allowed_roles,
+            "allowed_users": self.allowed_users
+        })
+    }
+}
+
+/// Where the bot answers besides DMs (config `private_mode`)
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum PrivateMode {
+    /// DMs only; guild interactions are asked to DM the bot
     DmOnly,
+    /// DMs plus the guild channels listed in `allowed_channels`
+    GuildAllowlist(Vec<String>),
+    /// DMs and every guild channel
+    Open,
+}
+
+impl PrivateMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PrivateMode::DmOnly => "dm_only",
+            PrivateMode::GuildAllowlist(_) => "guild_allowlist",
+            PrivateMode::Open => "open",
+        }
+    }
+
+    /// Whether a guild channel may be used
+    fn allows_channel(&self, channel_id: &str) -> bool {
+        match self {
+            PrivateMode::DmOnly => false,
+            PrivateMode::GuildAllowlist(channels) => channels.iter().any(|c| c == channel_id),
+            PrivateMode::Open => true,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let allowed_channels = match self {
+            PrivateMode::GuildAllowlist(channels) => channels.clone(),
+            _ => Vec::new(),
+        };
+        serde_json::json!({
+            "private_mode": self.as_str(),
+            "allowed_channels": allowed_channels
+        })
+    }
+}
+
+/// Where an interaction was triggered (`interaction.context`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InteractionContext {
+    Guild,
+    BotDm,
+    PrivateChannel,
+}
+
+impl InteractionContext {
+    fn as_str(self) -> &'static str {
+        match self {
+            InteractionContext::Guild => "guild",
+            InteractionContext::BotDm => "bot_dm",
+            InteractionContext::PrivateChannel => "private_channel",
+        }
+    }
 }
 
 static CONFIG_CACHE: std::sync::Mutex<Option<DiscordConfig>> = std::sync::Mutex::new(None);
@@ -179,14 +464,46 @@ static STATS: std::sync::LazyLock<std::sync::Mutex<DiscordStats>> =
     std::sync::LazyLock::new(|| std::sync::Mutex::new(DiscordStats::default()));
 static GATEWAY_STATE: std::sync::LazyLock<std::sync::Mutex<GatewayState>> =
     std::sync::LazyLock::new(|| std::sync::Mutex::new(GatewayState::default()));
+static FAIR_QUEUE: std::sync::LazyLock<std::sync::Mutex<FairQueue>> =
+    std::sync::LazyLock::new(|| std::sync::Mutex::new(FairQueue::default()));
 static LOGGED_NO_TOKEN: AtomicBool = AtomicBool::new(false);
 static LOGGED_GATEWAY_CONNECT: AtomicBool = AtomicBool::new(false);
+static LOGGED_SECRET_FILE_ERROR: AtomicBool = AtomicBool::new(false);
 
 #[derive(Default, Clone)]
 struct DiscordStats {
     sent: u64,
     received: u64,
     gateway_connected: bool,
+    missed_heartbeat_acks: u64,
+    gateway_error: Option<String>,
+    /// Unix time the next IDENTIFY is allowed, while it is being held back
+    identify_throttled_until: Option<u64>,
+    /// Unix time the counters were last written to storage
+    persisted_at: u64,
+    /// Persisted counters were merged in, so writing them back is safe
+    stats_loaded: bool,
+}
+
+/// The counters of `DiscordStats` that survive restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoredStats {
+    #[serde(default)]
+    sent: u64,
+    #[serde(default)]
+    received: u64,
+    #[serde(default)]
+    missed_heartbeat_acks: u64,
+}
+
+/// Per-user scheduling of gateway messages: each poll emits at most one
+/// message per user, and a user's next message waits until the previous one
+/// was answered (or `USER_IN_FLIGHT_TTL_SECS` passed).
+#[derive(Default)]
+struct FairQueue {
+    pending: VecDeque<InboundMessage>,
+    /// user_id -> (conversation_id, started_at)
+    in_flight: HashMap<String, (String, u64)>,
 }
 
 #[derive(Default, Clone)]
@@ -195,8 +512,31 @@ struct GatewayState {
     heartbeat_interval_ms: Option<u64>,
     last_heartbeat: Option<Instant>,
     last_heartbeat_ack: bool,
+    missed_heartbeat_acks: u32,
     seq: Option<i64>,
+    /// From READY; with `seq` it lets a reconnect RESUME instead of IDENTIFY
+    session_id: Option<String>,
+    resume_gateway_url: Option<String>,
     connected: bool,
+    /// Connects since the last READY, checked against DISCORD_GATEWAY_MAX_RECONNECTS
+    connect_attempts: u32,
+    /// Reconnect limit reached; cleared by `channel_start` or re-auth
+    gave_up: bool,
+    /// Unix times of recent IDENTIFYs, for the local rate limit
+    identify_sent_at: VecDeque<u64>,
+    /// HELLO arrived but IDENTIFY is waiting for the rate limit window
+    identify_pending: bool,
+    /// Unix time before which no IDENTIFY goes out (after INVALID_SESSION)
+    identify_not_before: Option<u64>,
+}
+
+/// The part of `GatewayState` needed to RESUME, persisted in storage
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredGatewaySession {
+    session_id: String,
+    seq: i64,
+    #[serde(default)]
+    resume_gateway_url: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -261,6 +601,22 @@ fn storage_delete(key: &str) -> bool {
     unsafe { storage_delete_raw(key.as_ptr() as i32, key.len() as i32) == 0 }
 }
 
+/// Stored keys starting with `prefix` (the host returns a JSON array)
+fn storage_keys(prefix: &str) -> Vec<String> {
+    unsafe {
+        let ret = storage_keys_raw(
+            prefix.as_ptr() as i32,
+            prefix.len() as i32,
+            return_buffer_ptr(),
+        );
+        if ret > 0 {
+            serde_json::from_slice(return_buffer_bytes(ret)).unwrap_or_default()
+        } else {
+            Vec::new()
+        }
+    }
+}
+
 fn env_get(name: &str) -> Option<String> {
     unsafe {
         let len = env_get_raw(name.as_ptr() as i32, name.len() as i32, env_buffer_ptr());
@@ -275,8 +631,245 @@ fn env_get(name: &str) -> Option<String> {
     }
 }
 
+/// Read a file from the filesystem (if allowed by capabilities)
+fn fs_read(path: &str) -> Option<String> {
+    unsafe {
+        let ret = fs_read_raw(path.as_ptr() as i32, path.len() as i32, return_buffer_ptr());
+        if ret > 0 {
+            String::from_utf8(return_buffer_bytes(ret).to_vec()).ok()
+        } else {
+            None
+        }
+    }
+}
+
+/// Read `private_mode` (`dm_only` by default, `guild_allowlist` or `open`)
+/// and `allowed_channels` from a config object
+fn private_mode_from_config(cfg: &Value) -> Result<PrivateMode, String> {
+    let channels: Vec<String> = cfg
+        .get("allowed_channels")
+        .and_then(Value::as_array)
+        .map(|items| {
+            items
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|c| c.trim().to_string())
+                .filter(|c| !c.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    match cfg
+        .get("private_mode")
+        .and_then(Value::as_str)
+        .unwrap_or("dm_only")
+    {
+        "dm_only" => Ok(PrivateMode::DmOnly),
+        "open" => Ok(PrivateMode::Open),
+        "guild_allowlist" if channels.is_empty() => {
+            Err("private_mode guild_allowlist needs allowed_channels".to_string())
+        }
+        "guild_allowlist" => match channels.iter().find(|c| !is_snowflake(c)) {
+            Some(bad) => Err(format!(
+                "allowed_channels entry '{}' is not a channel id",
+                bad
+            )),
+            None => Ok(PrivateMode::GuildAllowlist(channels)),
+        },
+        other => Err(format!(
+            "unknown private_mode '{}' (expected dm_only, guild_allowlist or open)",
+            other
+        )),
+    }
+}
+
+/// Read `allowed_roles` and `allowed_users` (lists of snowflakes) from a
+/// config object
+fn access_control_from_config(cfg: &Value) -> Result<AccessControl, String> {
+    let list = |name: &str| -> Result<Vec<String>, String> {
+        let ids: Vec<String> = cfg
+            .get(name)
+            .and_then(Value::as_array)
+            .map(|items| {
+                items
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(|id| id.trim().to_string())
+                    .filter(|id| !id.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        match ids.iter().find(|id| !is_snowflake(id)) {
+            Some(bad) => Err(format!("{} entry '{}' is not a Discord id", name, bad)),
+            None => Ok(ids),
+        }
+    };
+    Ok(AccessControl {
+        allowed_roles: list("allowed_roles")?,
+        allowed_users: list("allowed_users")?,
+    })
+}
+
+/// Read `intents`, either a number or a list of intent names such as
+/// `["GUILDS", "DIRECT_MESSAGES", "MESSAGE_CONTENT"]`
+fn intents_from_config(cfg: &Value) -> Result<Option<u64>, String> {
+    let intents = match cfg.get("intents") {
+        None | Some(Value::Null) => return Ok(None),
+        Some(Value::Number(n)) => n
+            .as_u64()
+            .ok_or_else(|| "intents must be a non-negative integer".to_string())?,
+        Some(Value::Array(names)) => {
+            let mut intents = 0;
+            for name in names {
+                let name = name.as_str().ok_or("intents entries must be strings")?;
+                let bit = DISCORD_INTENT_NAMES
+                    .iter()
+                    .find(|(known, _)| known.eq_ignore_ascii_case(name.trim()))
+                    .map(|(_, bit)| *bit)
+                    .ok_or_else(|| format!("unknown intent '{}'", name))?;
+                intents |= bit;
+            }
+            intents
+        }
+        Some(_) => return Err("intents must be a number or a list of names".to_string()),
+    };
+    let known = DISCORD_INTENT_NAMES
+        .iter()
+        .fold(0, |acc, (_, bit)| acc | bit);
+    if intents & !known != 0 {
+        return Err(format!("intents {} has unknown bits", intents));
+    }
+    if intents & DISCORD_INTENT_GUILD_MESSAGES != 0 && intents & DISCORD_INTENT_MESSAGE_CONTENT == 0
+    {
+        return Err("intents with GUILD_MESSAGES also need MESSAGE_CONTENT".to_string());
+    }
+    Ok(Some(intents))
+}
+
+/// Validate a config, cache it and persist it for future loads
+fn apply_config(
+    app_id: &str,
+    public_key: &str,
+    bot_token: Option<&str>,
+    private_mode: PrivateMode,
+    access: AccessControl,
+    intents: Option<u64>,
+) -> Result<(), String> {
+    if let Some(err) = public_key_error(public_key) {
+        return Err(err);
+    }
+    storage_set("discord_private_mode", &private_mode.to_json().to_string());
+    storage_set("discord_access_control", &access.to_json().to_string());
+    match intents {
+        Some(intents) => storage_set("discord_gateway_intents", &intents.to_string()),
+        None => storage_delete("discord_gateway_intents"),
+    };
+    if let Ok(mut guard) = CONFIG_CACHE.lock() {
+        *guard = Some(DiscordConfig {
+            application_id: app_id.to_string(),
+            public_key: public_key.to_string(),
+            bot_token: bot_token.map(str::to_string),
+            private_mode,
+            access,
+            intents,
+        });
+    }
+    storage_set("discord_application_id", app_id);
+    storage_set("discord_public_key", public_key);
+    if let Some(token) = bot_token {
+        storage_set("discord_bot_token", token);
+    }
+    Ok(())
+}
+
+/// Load `{application_id, public_key, bot_token?, private_mode?,
+/// allowed_channels?, allowed_roles?, allowed_users?, intents?}` from the
+/// JSON file named by `DISCORD_CONFIG_FILE`.
+/// Returns whether a valid config was applied.
+fn load_config_file() -> bool {
+    let path = match env_get("DISCORD_CONFIG_FILE") {
+        Some(path) => path,
+        None => return false,
+    };
+    let result = fs_read(&path)
+        .ok_or_else(|| "could not be read".to_string())
+        .and_then(|content| {
+            serde_json::from_str::<Value>(&content).map_err(|e| format!("is not JSON: {}", e))
+        })
+        .and_then(|cfg| {
+            let field = |name: &str| {
+                cfg.get(name)
+                    .and_then(Value::as_str)
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+            };
+            let app_id = field("application_id").ok_or("is missing application_id")?;
+            let public_key = field("public_key").ok_or("is missing public_key")?;
+            let mode = private_mode_from_config(&cfg)?;
+            let access = access_control_from_config(&cfg)?;
+            let intents = intents_from_config(&cfg)?;
+            apply_config(
+                app_id,
+                public_key,
+                field("bot_token"),
+                mode,
+                access,
+                intents,
+            )
+        });
+    match result {
+        Ok(()) => {
+            log_info(&format!("loaded discord config from {}", path));
+            true
+        }
+        Err(err) => {
+            log_error(&format!("DISCORD_CONFIG_FILE {} {}", path, err));
+            false
+        }
+    }
+}
+
+/// Resolve a secret from `<NAME>_FILE` (Docker/K8s secret mounts) before
+/// falling back to the `<NAME>` env var itself.
+fn env_or_file(name: &str) -> Option<String> {
+    let file_var = format!("{}_FILE", name);
+    if let Some(path) = env_get(&file_var) {
+        if let Some(value) = fs_read(&path)
+            .map(|content| content.trim().to_string())
+            .filter(|value| !value.is_empty())
+        {
+            return Some(value);
+        }
+        if !LOGGED_SECRET_FILE_ERROR.swap(true, Ordering::SeqCst) {
+            log_error(&format!(
+                "{} is set but {} could not be read",
+                file_var, path
+            ));
+        }
+    }
+    env_get(name)
+}
+
+/// `User-Agent` for Discord REST calls, overridable via `DISCORD_USER_AGENT`
+fn user_agent() -> String {
+    match env_get("DISCORD_USER_AGENT") {
+        Some(agent) if !agent.trim().is_empty() && !agent.contains(['\r', '\n']) => agent,
+        _ => format!(
+            "DiscordBot (https://github.com/thoughtoinnovate/tark, {})",
+            env!("CARGO_PKG_VERSION")
+        ),
+    }
+}
+
 fn http_post(url: &str, body: &str, headers: &[(String, String)]) -> Option<HttpResponse> {
-    let headers_json = serde_json::to_string(headers).unwrap_or_default();
+    let mut headers = headers.to_vec();
+    if header_value(&headers, "User-Agent").is_none() {
+        headers.push(("User-Agent".to_string(), user_agent()));
+    }
+    #[cfg(test)]
+    if let Some(resp) = mock_http_post(url, body, &headers) {
+        return Some(resp);
+    }
+    let headers_json = serde_json::to_string(&headers).unwrap_or_default();
     unsafe {
         let ret = http_post_raw(
             url.as_ptr() as i32,
@@ -313,8 +906,10 @@ fn ws_connect(url: &str, headers: &[(String, String)]) -> Result<u64, String> {
         if ret <= 0 {
             return Err("ws_connect failed".to_string());
         }
-        let payload = String::from_utf8(return_buffer_bytes(ret).to_vec()).map_err(|_| "bad ws response".to_string())?;
-        let resp: WsResponse = serde_json::from_str(&payload).map_err(|_| "bad ws json".to_string())?;
+        let payload = String::from_utf8(return_buffer_bytes(ret).to_vec())
+            .map_err(|_| "bad ws response".to_string())?;
+        let resp: WsResponse =
+            serde_json::from_str(&payload).map_err(|_| "bad ws json".to_string())?;
         if !resp.ok {
             return Err(resp.error.unwrap_or_else(|| "ws_connect error".to_string()));
         }
@@ -333,8 +928,10 @@ fn ws_send(handle: u64, data: &str) -> Result<(), String> {
         if ret <= 0 {
             return Err("ws_send failed".to_string());
         }
-        let payload = String::from_utf8(return_buffer_bytes(ret).to_vec()).map_err(|_| "bad ws response".to_string())?;
-        let resp: WsResponse = serde_json::from_str(&payload).map_err(|_| "bad ws json".to_string())?;
+        let payload = String::from_utf8(return_buffer_bytes(ret).to_vec())
+            .map_err(|_| "bad ws response".to_string())?;
+        let resp: WsResponse =
+            serde_json::from_str(&payload).map_err(|_| "bad ws json".to_string())?;
         if !resp.ok {
             return Err(resp.error.unwrap_or_else(|| "ws_send error".to_string()));
         }
@@ -353,8 +950,10 @@ fn ws_recv(handle: u64, timeout_ms: u64, max_bytes: u64) -> Result<WsResponse, S
         if ret <= 0 {
             return Err("ws_recv failed".to_string());
         }
-        let payload = String::from_utf8(return_buffer_bytes(ret).to_vec()).map_err(|_| "bad ws response".to_string())?;
-        let resp: WsResponse = serde_json::from_str(&payload).map_err(|_| "bad ws json".to_string())?;
+        let payload = String::from_utf8(return_buffer_bytes(ret).to_vec())
+            .map_err(|_| "bad ws response".to_string())?;
+        let resp: WsResponse =
+            serde_json::from_str(&payload).map_err(|_| "bad ws json".to_string())?;
         if !resp.ok {
             return Err(resp.error.unwrap_or_else(|| "ws_recv error".to_string()));
         }
@@ -396,7 +995,7 @@ fn get_public_key() -> Option<String> {
     if let Some(key) = storage_get("discord_public_key") {
         return Some(key);
     }
-    env_get("DISCORD_PUBLIC_KEY")
+    env_or_file("DISCORD_PUBLIC_KEY")
 }
 
 fn get_bot_token() -> Option<String> {
@@ -410,31 +1009,114 @@ fn get_bot_token() -> Option<String> {
     if let Some(token) = storage_get("discord_bot_token") {
         return Some(token);
     }
-    env_get("DISCORD_BOT_TOKEN")
+    env_or_file("DISCORD_BOT_TOKEN")
+}
+
+/// Consecutive missed HEARTBEAT_ACKs tolerated before the gateway is reset
+/// (`DISCORD_MAX_MISSED_HEARTBEAT_ACKS`, default 1).
+fn max_missed_heartbeat_acks() -> u32 {
+    env_get("DISCORD_MAX_MISSED_HEARTBEAT_ACKS")
+        .and_then(|v| v.trim().parse::<u32>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_MAX_MISSED_HEARTBEAT_ACKS)
+}
+
+/// Shortest heartbeat interval `DISCORD_HEARTBEAT_INTERVAL_MS` may set
+const MIN_HEARTBEAT_INTERVAL_MS: u64 = 5000;
+
+/// Heartbeat interval to use for the interval Discord advertised in HELLO.
+/// `DISCORD_HEARTBEAT_INTERVAL_MS` can shorten it to keep NAT mappings or
+/// proxies from dropping an idle socket. This is non-standard, so only use it
+/// when connections drop early. It never lengthens the interval.
+fn heartbeat_interval_ms(advertised_ms: u64) -> u64 {
+    match env_get("DISCORD_HEARTBEAT_INTERVAL_MS").and_then(|v| v.trim().parse::<u64>().ok()) {
+        Some(override_ms) => override_ms
+            .max(MIN_HEARTBEAT_INTERVAL_MS)
+            .min(advertised_ms),
+        None => advertised_ms,
+    }
+}
+
+/// `DISCORD_GATEWAY_MAX_RECONNECTS`: reconnects allowed without a READY
+/// in between before the gateway gives up (default: unlimited)
+fn max_gateway_reconnects() -> Option<u32> {
+    env_get("DISCORD_GATEWAY_MAX_RECONNECTS").and_then(|v| v.trim().parse::<u32>().ok())
+}
+
+/// `DISCORD_EPHEMERAL_DEFAULT=1` makes interaction replies ephemeral unless
+/// the command passes `public:true` (default: public).
+fn ephemeral_default() -> bool {
+    matches!(
+        env_get("DISCORD_EPHEMERAL_DEFAULT").as_deref(),
+        Some("1") | Some("true") | Some("yes")
+    )
 }
 
 fn private_mode() -> PrivateMode {
-    PrivateMode::DmOnly
+    if let Ok(guard) = CONFIG_CACHE.lock() {
+        if let Some(cfg) = guard.as_ref() {
+            return cfg.private_mode.clone();
+        }
+    }
+    storage_get("discord_private_mode")
+        .and_then(|raw| serde_json::from_str::<Value>(&raw).ok())
+        .and_then(|cfg| private_mode_from_config(&cfg).ok())
+        .unwrap_or(PrivateMode::DmOnly)
+}
+
+fn configured_intents() -> Option<u64> {
+    if let Ok(guard) = CONFIG_CACHE.lock() {
+        if let Some(cfg) = guard.as_ref() {
+            return cfg.intents;
+        }
+    }
+    storage_get("discord_gateway_intents").and_then(|raw| raw.trim().parse().ok())
+}
+
+fn access_control() -> AccessControl {
+    if let Ok(guard) = CONFIG_CACHE.lock() {
+        if let Some(cfg) = guard.as_ref() {
+            return cfg.access.clone();
+        }
+    }
+    storage_get("discord_access_control")
+        .and_then(|raw| serde_json::from_str::<Value>(&raw).ok())
+        .and_then(|cfg| access_control_from_config(&cfg).ok())
+        .unwrap_or_default()
+}
+
+/// Check the access allowlist and log the decision
+fn is_authorized(user_id: &str, roles: &[String]) -> bool {
+    let access = access_control();
+    let allowed = access.allows(user_id, roles);
+    if !(access.allowed_roles.is_empty() && access.allowed_users.is_empty()) {
+        log_info(&format!(
+            "access check for {}: {}",
+            user_id,
+            if allowed { "allowed" } else { "denied" }
+        ));
+    }
+    allowed
 }
 
 fn load_oauth_token() -> Option<(String, String, bool)> {
     if let Ok(guard) = TOKEN_CACHE.lock() {
         if let Some(tokens) = guard.as_ref() {
-            let token_type = tokens.token_type.clone().unwrap_or_else(|| "Bearer".to_string());
-            let expired = tokens
-                .expires_at
-                .map(|ts| now_ts() >= ts)
-                .unwrap_or(false);
+            let token_type = tokens
+                .token_type
+                .clone()
+                .unwrap_or_else(|| "Bearer".to_string());
+            let expired = tokens.expires_at.map(|ts| now_ts() >= ts).unwrap_or(false);
             return Some((tokens.access_token.clone(), token_type, expired));
         }
     }
-    let payload = storage_get("discord_oauth_tokens")?;
+    let payload = storage_get("discord_oauth_tokens").or_else(|| {
+        let path = env_get("DISCORD_OAUTH_TOKENS_FILE")?;
+        fs_read(&path)
+    })?;
     let tokens: OAuthTokens = serde_json::from_str(&payload).ok()?;
     let token_type = tokens.token_type.unwrap_or_else(|| "Bearer".to_string());
-    let expired = tokens
-        .expires_at
-        .map(|ts| now_ts() >= ts)
-        .unwrap_or(false);
+    let expired = tokens.expires_at.map(|ts| now_ts() >= ts).unwrap_or(false);
     Some((tokens.access_token, token_type, expired))
 }
 
@@ -453,13 +1135,33 @@ fn load_interaction_token(channel_id: &str) -> Option<String> {
     let key = format!("discord_interaction_token:{}", channel_id);
     let payload = storage_get(&key)?;
     let record: InteractionToken = serde_json::from_str(&payload).ok()?;
-    if now_ts().saturating_sub(record.created_at) > 15 * 60 {
+    if now_ts().saturating_sub(record.created_at) > INTERACTION_TOKEN_TTL_SECS {
         let _ = storage_delete(&key);
         return None;
     }
     Some(record.token)
 }
 
+fn conversation_activity_key(conversation_id: &str) -> String {
+    format!("discord_conversation:{}", conversation_id)
+}
+
+fn load_conversation_activity(conversation_id: &str) -> ConversationActivity {
+    storage_get(&conversation_activity_key(conversation_id))
+        .and_then(|raw| serde_json::from_str(&raw).ok())
+        .unwrap_or_default()
+}
+
+/// Bump the inbound message count and last-activity time of a conversation
+fn record_conversation_activity(conversation_id: &str) {
+    let mut activity = load_conversation_activity(conversation_id);
+    activity.last_activity = now_ts();
+    activity.message_count += 1;
+    if let Ok(payload) = serde_json::to_string(&activity) {
+        storage_set(&conversation_activity_key(conversation_id), &payload);
+    }
+}
+
 fn header_value(headers: &[(String, String)], name: &str) -> Option<String> {
     headers
         .iter()
@@ -467,11 +1169,118 @@ fn header_value(headers: &[(String, String)], name: &str) -> Option<String> {
         .map(|(_, v)| v.clone())
 }
 
-fn verify_signature(headers: &[(String, String)], body: &str) -> bool {
-    let public_key = match get_public_key() {
-        Some(key) => key,
-        None => return false,
+/// Discord ids are snowflakes: 64-bit integers written as 17-20 digits
+fn is_snowflake(value: &str) -> bool {
+    (17..=20).contains(&value.len()) && value.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Channel a conversation lives in. Guild conversations are per user,
+/// `<channel_id>:<user_id>`; any other conversation id is the channel id.
+fn conversation_channel_id(conversation_id: &str) -> &str {
+    match conversation_id.split_once(':') {
+        Some((channel_id, user_id)) if is_snowflake(channel_id) && is_snowflake(user_id) => {
+            channel_id
+        }
+        _ => conversation_id,
+    }
+}
+
+/// Why a bot token cannot be a Discord token (three dot-separated base64url
+/// parts), if it is malformed. The host only offers POST, so the token is not
+/// checked against `/users/@me`.
+fn bot_token_error(token: &str) -> Option<String> {
+    let token = token.trim().strip_prefix("Bot ").unwrap_or(token.trim());
+    let parts: Vec<&str> = token.split('.').collect();
+    let well_formed = parts.len() == 3
+        && parts.iter().all(|part| {
+            !part.is_empty()
+                && part
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        });
+    if well_formed {
+        None
+    } else {
+        Some("discord bot_token does not look like a bot token".to_string())
+    }
+}
+
+/// Structured report for a `{application_id, public_key, bot_token?}` config
+fn validate_config(cfg: &Value) -> Value {
+    let field = |name: &str| {
+        cfg.get(name)
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
     };
+    let check = |error: Option<String>| match error {
+        None => serde_json::json!({ "ok": true }),
+        Some(error) => serde_json::json!({ "ok": false, "error": error }),
+    };
+
+    let application_id = check(match field("application_id") {
+        None => Some("application_id is missing".to_string()),
+        Some(id) if !is_snowflake(id) => {
+            Some("application_id is not a Discord snowflake".to_string())
+        }
+        Some(_) => None,
+    });
+    let public_key = check(match field("public_key") {
+        None => Some("public_key is missing".to_string()),
+        Some(key) => public_key_error(key),
+    });
+    let bot_token = field("bot_token").map(|token| check(bot_token_error(token)));
+    let private_mode = check(private_mode_from_config(cfg).err());
+    let access = check(access_control_from_config(cfg).err());
+    let intents = check(intents_from_config(cfg).err());
+
+    let valid = application_id["ok"] == Value::Bool(true)
+        && public_key["ok"] == Value::Bool(true)
+        && private_mode["ok"] == Value::Bool(true)
+        && access["ok"] == Value::Bool(true)
+        && intents["ok"] == Value::Bool(true)
+        && bot_token
+            .as_ref()
+            .is_none_or(|check| check["ok"] == Value::Bool(true));
+    serde_json::json!({
+        "valid": valid,
+        "application_id": application_id,
+        "public_key": public_key,
+        "bot_token": bot_token,
+        "private_mode": private_mode,
+        "access": access,
+        "intents": intents
+    })
+}
+
+/// Why a configured public key cannot be used, if it is malformed
+fn public_key_error(public_key: &str) -> Option<String> {
+    match hex::decode(public_key.trim()) {
+        Ok(bytes) if bytes.len() == 32 => None,
+        Ok(bytes) => Some(format!(
+            "discord public_key must be 32 bytes (64 hex chars), got {} bytes",
+            bytes.len()
+        )),
+        Err(_) => Some("discord public_key is not valid hex".to_string()),
+    }
+}
+
+fn verify_signature(headers: &[(String, String)], body: &str) -> bool {
+    match get_public_key() {
+        Some(public_key) => verify_signature_at(&public_key, headers, body, now_ts()),
+        None => false,
+    }
+}
+
+/// Check the ed25519 signature over `timestamp + body`, rejecting requests
+/// whose timestamp is more than SIGNATURE_TOLERANCE_SECS away from `now` so
+/// a captured request cannot be replayed later
+fn verify_signature_at(
+    public_key: &str,
+    headers: &[(String, String)],
+    body: &str,
+    now: u64,
+) -> bool {
     let signature_hex = match header_value(headers, "x-signature-ed25519") {
         Some(sig) => sig,
         None => return false,
@@ -480,6 +1289,10 @@ fn verify_signature(headers: &[(String, String)], body: &str) -> bool {
         Some(ts) => ts,
         None => return false,
     };
+    match timestamp.trim().parse::<u64>() {
+        Ok(ts) if ts.abs_diff(now) <= SIGNATURE_TOLERANCE_SECS => {}
+        _ => return false,
+    }
 
     let public_key_bytes = match hex::decode(public_key) {
         Ok(bytes) => bytes,
@@ -520,13 +1333,78 @@ fn respond_json(response: &WebhookResponse, ret_ptr: i32) -> i32 {
 fn record_received() {
     if let Ok(mut stats) = STATS.lock() {
         stats.received = stats.received.saturating_add(1);
+        persist_stats(&mut stats, false);
     }
 }
 
 fn record_sent() {
     if let Ok(mut stats) = STATS.lock() {
         stats.sent = stats.sent.saturating_add(1);
+        persist_stats(&mut stats, false);
+    }
+}
+
+fn record_missed_heartbeat_ack() {
+    if let Ok(mut stats) = STATS.lock() {
+        stats.missed_heartbeat_acks = stats.missed_heartbeat_acks.saturating_add(1);
+        persist_stats(&mut stats, false);
+    }
+}
+
+/// `DISCORD_STATS_PERSIST_SECS`: minimum seconds between writes of the
+/// counters to storage (default 30, 0 writes on every update)
+fn stats_persist_secs() -> u64 {
+    env_get("DISCORD_STATS_PERSIST_SECS")
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_STATS_PERSIST_SECS)
+}
+
+/// Write the counters to storage, at most once per `stats_persist_secs()`
+/// unless `force` is set. Called with the STATS lock held, so concurrent
+/// updates cannot write an older snapshot over a newer one.
+fn persist_stats(stats: &mut DiscordStats, force: bool) {
+    if !stats.stats_loaded {
+        merge_stored_stats(stats);
+    }
+    let now = now_ts();
+    if !force && now.saturating_sub(stats.persisted_at) < stats_persist_secs() {
+        return;
+    }
+    let stored = StoredStats {
+        sent: stats.sent,
+        received: stats.received,
+        missed_heartbeat_acks: stats.missed_heartbeat_acks,
+    };
+    if let Ok(json) = serde_json::to_string(&stored) {
+        storage_set(STATS_KEY, &json);
+        stats.persisted_at = now;
+    }
+}
+
+/// Restore the counters persisted by an earlier run
+fn load_stats() {
+    if let Ok(mut stats) = STATS.lock() {
+        merge_stored_stats(&mut stats);
+    }
+}
+
+/// Add the persisted counters to the ones counted so far in this run. Runs
+/// once per process, so nothing is counted twice.
+fn merge_stored_stats(stats: &mut DiscordStats) {
+    if stats.stats_loaded {
+        return;
     }
+    stats.stats_loaded = true;
+    let Some(stored) =
+        storage_get(STATS_KEY).and_then(|raw| serde_json::from_str::<StoredStats>(&raw).ok())
+    else {
+        return;
+    };
+    stats.sent = stats.sent.saturating_add(stored.sent);
+    stats.received = stats.received.saturating_add(stored.received);
+    stats.missed_heartbeat_acks = stats
+        .missed_heartbeat_acks
+        .saturating_add(stored.missed_heartbeat_acks);
 }
 
 fn set_gateway_connected(connected: bool) {
@@ -535,6 +1413,36 @@ fn set_gateway_connected(connected: bool) {
     }
 }
 
+/// Record the IDENTIFY throttle for the widget; true if it just started
+fn set_identify_throttled(until: Option<u64>) -> bool {
+    match STATS.lock() {
+        Ok(mut stats) => {
+            let started = until.is_some() && stats.identify_throttled_until.is_none();
+            stats.identify_throttled_until = until;
+            started
+        }
+        Err(_) => false,
+    }
+}
+
+fn set_gateway_error(error: Option<String>) {
+    if let Ok(mut stats) = STATS.lock() {
+        stats.gateway_error = error;
+    }
+}
+
+/// Let the gateway connect again after it hit the reconnect limit
+fn clear_gateway_give_up() {
+    if let Ok(mut state) = GATEWAY_STATE.lock() {
+        if state.gave_up {
+            log_info("gateway reconnect limit cleared");
+        }
+        state.gave_up = false;
+        state.connect_attempts = 0;
+    }
+    set_gateway_error(None);
+}
+
 fn write_string(ptr: i32, value: &str) -> i32 {
     unsafe {
         let bytes = value.as_bytes();
@@ -570,20 +1478,95 @@ pub extern "C" fn channel_info(ret_ptr: i32) -> i32 {
 
 #[no_mangle]
 pub extern "C" fn channel_start() -> i32 {
+    clear_gateway_give_up();
+    load_config_file();
+    load_stats();
     log_info("discord channel plugin started");
     0
 }
 
+/// Called by tark after install/enable: resolves the config into the cache
+/// and opens the gateway socket so the first message does not pay for it.
+/// Returns a readiness summary.
 #[no_mangle]
-pub extern "C" fn channel_stop() -> i32 {
-    log_info("discord channel plugin stopped");
-    0
-}
+pub extern "C" fn channel_warm(ret_ptr: i32) -> i32 {
+    let application_id = get_application_id();
+    let public_key = get_public_key();
+    let public_key_status = match public_key.as_deref() {
+        None => "missing",
+        Some(key) if public_key_error(key).is_some() => "invalid",
+        Some(_) => "ok",
+    };
+    let bot_token = get_bot_token();
+    let mode = private_mode();
+    let access = access_control();
+    let intents = configured_intents();
+
+    if let (Some(app_id), Some(key), "ok") = (&application_id, &public_key, public_key_status) {
+        if let Ok(mut guard) = CONFIG_CACHE.lock() {
+            if guard.is_none() {
+                *guard = Some(DiscordConfig {
+                    application_id: app_id.clone(),
+                    public_key: key.clone(),
+                    bot_token: bot_token.clone(),
+                    private_mode: mode,
+                    access,
+                    intents,
+                });
+            }
+        }
+    }
 
-#[no_mangle]
+    let gateway = if bot_token.is_none() {
+        "unavailable".to_string()
+    } else {
+        match GATEWAY_STATE.lock() {
+            Ok(mut state) => match ensure_gateway_socket(&mut state) {
+                Ok(()) => "connecting".to_string(),
+                Err(err) => format!("connect failed: {}", err),
+            },
+            Err(_) => "unavailable".to_string(),
+        }
+    };
+
+    let ready = application_id.is_some() && public_key_status == "ok";
+    log_info(&format!(
+        "discord warm: ready={} public_key={} gateway={}",
+        ready, public_key_status, gateway
+    ));
+    let payload = serde_json::json!({
+        "ready": ready,
+        "application_id": application_id.is_some(),
+        "public_key": public_key_status,
+        "bot_token": bot_token.is_some(),
+        "gateway": gateway
+    });
+    write_string(ret_ptr, &payload.to_string())
+}
+
+#[no_mangle]
+pub extern "C" fn channel_stop() -> i32 {
+    if let Ok(mut stats) = STATS.lock() {
+        persist_stats(&mut stats, true);
+    }
+    if let Ok(state) = GATEWAY_STATE.lock() {
+        save_gateway_session(&state);
+    }
+    log_info("discord channel plugin stopped");
+    0
+}
+
+/// Returns: 1 = authenticated, 2 = not authenticated, 3 = expired,
+/// 4 = misconfigured (public key present but malformed)
+#[no_mangle]
 pub extern "C" fn channel_auth_status() -> i32 {
-    if get_public_key().is_none() {
-        return 2;
+    let public_key = match get_public_key() {
+        Some(key) => key,
+        None => return 2,
+    };
+    if let Some(err) = public_key_error(&public_key) {
+        log_error(&err);
+        return 4;
     }
 
     if get_bot_token().is_some() {
@@ -604,27 +1587,35 @@ pub extern "C" fn channel_auth_status() -> i32 {
 pub extern "C" fn channel_auth_init(ptr: i32, len: i32) -> i32 {
     let payload = read_string(ptr, len);
     if let Ok(value) = serde_json::from_str::<Value>(&payload) {
+        if value.get("validate_only").and_then(Value::as_bool) == Some(true) {
+            let report = validate_config(value.get("config").unwrap_or(&Value::Null));
+            log_info(&format!("discord config validation: {}", report));
+            return if report["valid"] == Value::Bool(true) {
+                0
+            } else {
+                -2
+            };
+        }
+        clear_gateway_give_up();
         let mut config_set = false;
-        if let Some(cfg) = value.get("config").and_then(Value::as_object) {
+        if let Some(cfg) = value.get("config").filter(|c| c.is_object()) {
             let app_id = cfg.get("application_id").and_then(Value::as_str);
             let public_key = cfg.get("public_key").and_then(Value::as_str);
             let bot_token = cfg.get("bot_token").and_then(Value::as_str);
             if let (Some(app_id), Some(public_key)) = (app_id, public_key) {
-                if let Ok(mut guard) = CONFIG_CACHE.lock() {
-                    *guard = Some(DiscordConfig {
-                        application_id: app_id.to_string(),
-                        public_key: public_key.to_string(),
-                        bot_token: bot_token.map(str::to_string),
-                    });
-                    config_set = true;
-                }
-                // Persist config for future loads.
-                storage_set("discord_application_id", app_id);
-                storage_set("discord_public_key", public_key);
-                if let Some(token) = bot_token {
-                    storage_set("discord_bot_token", token);
+                let applied = private_mode_from_config(cfg).and_then(|mode| {
+                    let access = access_control_from_config(cfg)?;
+                    let intents = intents_from_config(cfg)?;
+                    apply_config(app_id, public_key, bot_token, mode, access, intents)
+                });
+                if let Err(err) = applied {
+                    log_error(&err);
+                    return -2;
                 }
+                config_set = true;
             }
+        } else {
+            config_set = load_config_file();
         }
 
         if let Some(tokens) = value.get("tokens") {
@@ -657,12 +1648,152 @@ pub extern "C" fn channel_auth_init(ptr: i32, len: i32) -> i32 {
     -1
 }
 
+/// Definition of the `/tark` slash command, matching what `extract_command`
+/// and `interaction_ephemeral` read
+fn tark_command_definition() -> Value {
+    serde_json::json!({
+        "name": "tark",
+        "type": 1,
+        "description": "Talk to Tark",
+        "integration_types": [0, 1],
+        "contexts": [0, 1, 2],
+        "options": [
+            { "type": 3, "name": "prompt", "description": "What to ask" },
+            { "type": 3, "name": "command", "description": "Tark command, e.g. status" },
+            { "type": 3, "name": "model", "description": "Model to use" },
+            { "type": 5, "name": "public", "description": "Show the reply to everyone" }
+        ]
+    })
+}
+
+/// Register (or update) the `/tark` slash command with the bot token.
+/// Uses the single-command POST, which upserts by name, since the host only
+/// offers POST and the bulk overwrite needs PUT.
+#[no_mangle]
+pub extern "C" fn channel_register_commands(ret_ptr: i32) -> i32 {
+    let result = match (get_application_id(), get_bot_token()) {
+        (None, _) => Err("missing application id".to_string()),
+        (_, None) => Err("missing bot token".to_string()),
+        (Some(app_id), Some(bot_token)) => {
+            let url = format!(
+                "https://discord.com/api/v10/applications/{}/commands",
+                app_id
+            );
+            let headers = vec![
+                ("Content-Type".to_string(), "application/json".to_string()),
+                ("Authorization".to_string(), format!("Bot {}", bot_token)),
+            ];
+            let body = tark_command_definition().to_string();
+            match http_post(&url, &body, &headers) {
+                None => Err("no response from discord".to_string()),
+                Some(resp) if resp.status >= 200 && resp.status < 300 => {
+                    let command = serde_json::from_str::<Value>(&resp.body).unwrap_or(Value::Null);
+                    Ok(vec![serde_json::json!({
+                        "name": command.get("name").cloned().unwrap_or(Value::Null),
+                        "id": command.get("id").cloned().unwrap_or(Value::Null)
+                    })])
+                }
+                Some(resp) => Err(format!("HTTP {}: {}", resp.status, resp.body)),
+            }
+        }
+    };
+    let payload = match result {
+        Ok(commands) => {
+            log_info("registered /tark command");
+            serde_json::json!({ "success": true, "commands": commands, "error": null })
+        }
+        Err(err) => {
+            log_error(&format!("command registration failed: {}", err));
+            serde_json::json!({ "success": false, "commands": [], "error": err })
+        }
+    };
+    write_string(ret_ptr, &payload.to_string())
+}
+
+/// Dry run of `channel_auth_init`: checks `{config: {...}}` and returns a
+/// report without persisting anything.
+#[no_mangle]
+pub extern "C" fn channel_auth_validate(ptr: i32, len: i32, ret_ptr: i32) -> i32 {
+    let payload = read_string(ptr, len);
+    let value = serde_json::from_str::<Value>(&payload).unwrap_or(Value::Null);
+    let report = validate_config(value.get("config").unwrap_or(&Value::Null));
+    write_string(ret_ptr, &report.to_string())
+}
+
 #[no_mangle]
 pub extern "C" fn channel_auth_logout() -> i32 {
     let _ = storage_delete("discord_oauth_tokens");
     0
 }
 
+/// Zero the message counters, in memory and in storage. Returns the totals
+/// before the reset as `{sent, received, missed_heartbeat_acks}`.
+#[no_mangle]
+pub extern "C" fn channel_reset_stats(ret_ptr: i32) -> i32 {
+    let previous = match STATS.lock() {
+        Ok(mut stats) => {
+            merge_stored_stats(&mut stats);
+            let previous = StoredStats {
+                sent: stats.sent,
+                received: stats.received,
+                missed_heartbeat_acks: stats.missed_heartbeat_acks,
+            };
+            stats.sent = 0;
+            stats.received = 0;
+            stats.missed_heartbeat_acks = 0;
+            storage_delete(STATS_KEY);
+            previous
+        }
+        Err(_) => StoredStats::default(),
+    };
+    log_info("discord stats reset");
+    let payload = serde_json::to_string(&previous).unwrap_or_else(|_| "{}".to_string());
+    write_string(ret_ptr, &payload)
+}
+
+/// Support-recommended recovery: drop stored config/tokens, per-conversation
+/// keys and all in-memory caches, closing the gateway.
+#[no_mangle]
+pub extern "C" fn plugin_reset(ret_ptr: i32) -> i32 {
+    let cleared: Vec<String> = STORAGE_KEYS
+        .iter()
+        .map(|key| key.to_string())
+        .chain(
+            CONVERSATION_KEY_PREFIXES
+                .iter()
+                .flat_map(|prefix| storage_keys(prefix)),
+        )
+        .filter(|key| storage_delete(key))
+        .collect();
+
+    if let Ok(mut guard) = CONFIG_CACHE.lock() {
+        *guard = None;
+    }
+    if let Ok(mut guard) = TOKEN_CACHE.lock() {
+        *guard = None;
+    }
+    if let Ok(mut state) = GATEWAY_STATE.lock() {
+        reset_gateway(&mut state);
+        forget_gateway_session(&mut state);
+    }
+    if let Ok(mut stats) = STATS.lock() {
+        *stats = DiscordStats::default();
+    }
+    if let Ok(mut queue) = FAIR_QUEUE.lock() {
+        *queue = FairQueue::default();
+    }
+    LOGGED_NO_TOKEN.store(false, Ordering::SeqCst);
+    LOGGED_GATEWAY_CONNECT.store(false, Ordering::SeqCst);
+    LOGGED_SECRET_FILE_ERROR.store(false, Ordering::SeqCst);
+
+    log_info(&format!(
+        "discord plugin state reset (cleared {:?})",
+        cleared
+    ));
+    let payload = serde_json::json!({ "cleared": cleared });
+    write_string(ret_ptr, &payload.to_string())
+}
+
 #[no_mangle]
 pub extern "C" fn channel_handle_gateway_event(ptr: i32, len: i32, ret_ptr: i32) -> i32 {
     let payload = read_string(ptr, len);
@@ -678,13 +1809,63 @@ pub extern "C" fn channel_handle_gateway_event(ptr: i32, len: i32, ret_ptr: i32)
 
 #[no_mangle]
 pub extern "C" fn channel_poll(ret_ptr: i32) -> i32 {
-    let messages = gateway_poll();
+    let messages = schedule_fair(gateway_poll());
+    for msg in &messages {
+        record_conversation_activity(&msg.conversation_id);
+    }
     match serde_json::to_string(&messages) {
         Ok(json) => write_string(ret_ptr, &json),
         Err(_) => -1,
     }
 }
 
+/// Known conversations with their last activity, inbound message count and
+/// whether a follow-up interaction token is still usable, most recent first.
+#[no_mangle]
+pub extern "C" fn channel_list_conversations(ret_ptr: i32) -> i32 {
+    let now = now_ts();
+    let mut conversations: HashMap<String, (ConversationActivity, bool)> = HashMap::new();
+
+    let activity_prefix = conversation_activity_key("");
+    for key in storage_keys(&activity_prefix) {
+        let id = key[activity_prefix.len()..].to_string();
+        let activity = load_conversation_activity(&id);
+        conversations.insert(id, (activity, false));
+    }
+
+    let token_prefix = "discord_interaction_token:";
+    for key in storage_keys(token_prefix) {
+        let record = match storage_get(&key)
+            .and_then(|raw| serde_json::from_str::<InteractionToken>(&raw).ok())
+        {
+            Some(record) => record,
+            None => continue,
+        };
+        let entry = conversations
+            .entry(key[token_prefix.len()..].to_string())
+            .or_default();
+        entry.0.last_activity = entry.0.last_activity.max(record.created_at);
+        entry.1 = now.saturating_sub(record.created_at) <= INTERACTION_TOKEN_TTL_SECS;
+    }
+
+    let mut list: Vec<(String, (ConversationActivity, bool))> = conversations.into_iter().collect();
+    list.sort_by_key(|(_, (activity, _))| std::cmp::Reverse(activity.last_activity));
+    let conversations: Vec<Value> = list
+        .into_iter()
+        .map(|(id, (activity, token_active))| {
+            serde_json::json!({
+                "conversation_id": id,
+                "last_activity": activity.last_activity,
+                "idle_secs": now.saturating_sub(activity.last_activity),
+                "message_count": activity.message_count,
+                "interaction_token_active": token_active
+            })
+        })
+        .collect();
+    let payload = serde_json::json!({ "conversations": conversations });
+    write_string(ret_ptr, &payload.to_string())
+}
+
 #[no_mangle]
 pub extern "C" fn channel_widget_state(ret_ptr: i32) -> i32 {
     let stats = match STATS.lock() {
@@ -693,6 +1874,10 @@ pub extern "C" fn channel_widget_state(ret_ptr: i32) -> i32 {
     };
     let status = if stats.gateway_connected {
         "connected"
+    } else if stats.gateway_error.is_some() {
+        "failed"
+    } else if stats.identify_throttled_until.is_some() {
+        "throttled"
     } else {
         "disconnected"
     };
@@ -701,17 +1886,20 @@ pub extern "C" fn channel_widget_state(ret_ptr: i32) -> i32 {
         "messages": {
             "sent": stats.sent,
             "received": stats.received
-        }
+        },
+        "heartbeat": {
+            "missed_acks": stats.missed_heartbeat_acks
+        },
+        "identify": {
+            "throttled_until": stats.identify_throttled_until
+        },
+        "error": stats.gateway_error
     });
     write_string(ret_ptr, &payload.to_string())
 }
 
 #[no_mangle]
-pub extern "C" fn channel_handle_webhook(
-    req_ptr: i32,
-    req_len: i32,
-    ret_ptr: i32,
-) -> i32 {
+pub extern "C" fn channel_handle_webhook(req_ptr: i32, req_len: i32, ret_ptr: i32) -> i32 {
     let payload = read_string(req_ptr, req_len);
     let request: WebhookRequest = match serde_json::from_str(&payload) {
         Ok(req) => req,
@@ -746,6 +1934,17 @@ pub extern "C" fn channel_handle_webhook(
         return respond_json(&response, ret_ptr);
     }
 
+    if let Some(err) = get_public_key().and_then(|key| public_key_error(&key)) {
+        log_error(&err);
+        let response = WebhookResponse {
+            status: 500,
+            headers: vec![],
+            body: "discord public key misconfigured".to_string(),
+            messages: vec![],
+        };
+        return respond_json(&response, ret_ptr);
+    }
+
     if !verify_signature(&request.headers, &request.body) {
         let response = WebhookResponse {
             status: 401,
@@ -780,7 +1979,7 @@ pub extern "C" fn channel_handle_webhook(
         return respond_json(&response, ret_ptr);
     }
 
-    if interaction_type != 2 {
+    if !matches!(interaction_type, 2 | 3 | 5) {
         let response = WebhookResponse {
             status: 200,
             headers: vec![("Content-Type".to_string(), "application/json".to_string())],
@@ -811,8 +2010,9 @@ pub extern "C" fn channel_handle_webhook(
         .and_then(Value::as_str)
         .map(str::to_string);
     let mode = private_mode();
-    let is_guild = guild_id.is_some();
-    if is_guild && mode == PrivateMode::DmOnly {
+    let context = interaction_context(&payload);
+    let is_dm = context == InteractionContext::BotDm;
+    if !is_dm && !mode.allows_channel(&channel_id) {
         let response = WebhookResponse {
             status: 200,
             headers: vec![("Content-Type".to_string(), "application/json".to_string())],
@@ -822,38 +2022,96 @@ pub extern "C" fn channel_handle_webhook(
         return respond_json(&response, ret_ptr);
     }
 
-    let (text, command) = extract_command(&payload);
-    let conversation_id = if is_guild {
-        format!("{}:{}", channel_id, user_id)
-    } else {
+    if !is_authorized(&user_id, &roles) {
+        let response = WebhookResponse {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: "{\"type\":4,\"data\":{\"content\":\"You are not authorized to use Tark here.\",\"flags\":64}}".to_string(),
+            messages: vec![],
+        };
+        return respond_json(&response, ret_ptr);
+    }
+
+    let entitlements = extract_entitlements(&payload);
+    if let Some(sku_id) = premium_sku() {
+        if !has_entitlement(&entitlements, &sku_id) {
+            let response = WebhookResponse {
+                status: 200,
+                headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+                body: premium_required_response(&sku_id).to_string(),
+                messages: vec![],
+            };
+            return respond_json(&response, ret_ptr);
+        }
+    }
+
+    let component = (interaction_type == 3).then(|| extract_component(&payload));
+    let modal = (interaction_type == 5).then(|| extract_modal(&payload));
+    let (text, command) = match (&component, &modal) {
+        (Some(component), _) => (component.text(), Value::Null),
+        (_, Some(modal)) => (modal.text(), Value::Null),
+        _ => extract_command(&payload),
+    };
+    let conversation_id = if is_dm {
         channel_id.clone()
+    } else {
+        format!("{}:{}", channel_id, user_id)
     };
     if !interaction_token.is_empty() {
         store_interaction_token(&conversation_id, &interaction_token);
     }
-    let ephemeral = false;
+    let ephemeral = interaction_ephemeral(&payload);
     let attachments = extract_attachments_from_interaction(&payload);
-    let metadata = serde_json::json!({
+    let mut metadata = serde_json::json!({
         "discord": {
             "user_id": user_id.clone(),
             "channel_id": channel_id.clone(),
             "guild_id": guild_id.clone(),
+            "context": context.as_str(),
+            "authorizing_integration_owners": payload
+                .get("authorizing_integration_owners")
+                .cloned()
+                .unwrap_or(Value::Null),
             "roles": roles,
             "interaction_token": interaction_token,
             "ephemeral": ephemeral,
-            "attachments": attachments
+            "attachments": attachments,
+            "entitlements": entitlements
         },
         "tark_command": command
     });
+    if let Some(component) = &component {
+        metadata["discord"]["custom_id"] = Value::String(component.custom_id.clone());
+        metadata["discord"]["component_type"] = serde_json::json!(component.component_type);
+        metadata["discord"]["values"] = serde_json::json!(component.values);
+        metadata["discord"]["component_message_id"] = payload
+            .get("message")
+            .and_then(|m| m.get("id"))
+            .cloned()
+            .unwrap_or(Value::Null);
+    }
+    if let Some(modal) = &modal {
+        metadata["discord"]["modal"] = modal.to_json();
+    }
 
-    let inbound = InboundMessage {
-        conversation_id: conversation_id.clone(),
-        user_id,
-        text,
-        metadata_json: metadata.to_string(),
-    };
+    let messages = build_interaction_messages(&payload, &conversation_id, &user_id, text, metadata);
+    for msg in &messages {
+        record_conversation_activity(&msg.conversation_id);
+    }
 
-    let response_body = if ephemeral {
+    // Components are acked with DEFERRED_UPDATE_MESSAGE so the clicked
+    // message stays as it is until the reply edits or follows it.
+    // Modal submits must be answered with a message, so they get a short
+    // type 4 reply that channel_send later edits.
+    let response_body = if component.is_some() {
+        "{\"type\":6}".to_string()
+    } else if modal.is_some() {
+        let mut data = serde_json::json!({ "content": "Submitted." });
+        if ephemeral {
+            data["flags"] = serde_json::json!(64);
+        }
+        serde_json::json!({ "type": 4, "data": data }).to_string()
+    } else if ephemeral {
         "{\"type\":5,\"data\":{\"flags\":64}}".to_string()
     } else {
         "{\"type\":5}".to_string()
@@ -862,7 +2120,7 @@ pub extern "C" fn channel_handle_webhook(
         status: 200,
         headers: vec![("Content-Type".to_string(), "application/json".to_string())],
         body: response_body,
-        messages: vec![inbound],
+        messages,
     };
     respond_json(&response, ret_ptr)
 }
@@ -870,9 +2128,14 @@ pub extern "C" fn channel_handle_webhook(
 #[no_mangle]
 pub extern "C" fn channel_send(req_ptr: i32, req_len: i32, ret_ptr: i32) -> i32 {
     let payload = read_string(req_ptr, req_len);
-    let request: Value = match serde_json::from_str(&payload) {
+    write_string(ret_ptr, &send_message(&payload))
+}
+
+/// Deliver a `channel_send` request and return the result JSON
+fn send_message(payload: &str) -> String {
+    let request: Value = match serde_json::from_str(payload) {
         Ok(v) => v,
-        Err(_) => return write_string(ret_ptr, "{\"success\":false,\"error\":\"bad request\"}"),
+        Err(_) => return "{\"success\":false,\"error\":\"bad request\"}".to_string(),
     };
 
     let conversation_id = request
@@ -893,16 +2156,26 @@ pub extern "C" fn channel_send(req_ptr: i32, req_len: i32, ret_ptr: i32) -> i32
         .get("metadata_json")
         .and_then(Value::as_str)
         .unwrap_or("");
-    let (channel_id_override, ephemeral) = parse_send_metadata(metadata_json);
+    let send_metadata = parse_send_metadata(metadata_json);
+    let channel_id_override = send_metadata.channel_id.clone();
+    let ephemeral = send_metadata.ephemeral;
+    let components = send_metadata.components.as_deref();
+    let embeds = send_metadata.embeds.as_deref();
+    let allowed_mentions = &send_metadata.allowed_mentions;
+    let nonce = request
+        .get("nonce")
+        .and_then(Value::as_str)
+        .filter(|n| !n.is_empty())
+        .map(str::to_string);
     let mut tool_status: Option<ToolStatus> = None;
     let mut tool_key: Option<String> = None;
 
     if should_suppress_session_header(&conversation_id, &text) {
-        return write_string(ret_ptr, "{\"success\":true,\"message_id\":null,\"error\":null}");
+        return "{\"success\":true,\"message_id\":null,\"error\":null}".to_string();
     }
 
     if should_suppress_tool_output(&text) {
-        return write_string(ret_ptr, "{\"success\":true,\"message_id\":null,\"error\":null}");
+        return "{\"success\":true,\"message_id\":null,\"error\":null}".to_string();
     }
 
     text = strip_tool_summary(&text);
@@ -920,10 +2193,7 @@ pub extern "C" fn channel_send(req_ptr: i32, req_len: i32, ret_ptr: i32) -> i32
             if matches!(status, ToolStatus::Running) {
                 if let Some((state, stored_id, ts)) = tool_state.as_ref() {
                     if state == "done" && is_recent(*ts) {
-                        return write_string(
-                            ret_ptr,
-                            "{\"success\":true,\"message_id\":null,\"error\":null}",
-                        );
+                        return "{\"success\":true,\"message_id\":null,\"error\":null}".to_string();
                     }
                     if state == "running" {
                         message_id = Some(stored_id.clone());
@@ -933,145 +2203,184 @@ pub extern "C" fn channel_send(req_ptr: i32, req_len: i32, ret_ptr: i32) -> i32
                 }
             } else if let Some((state, stored_id, ts)) = tool_state.as_ref() {
                 if state == "done" && is_recent(*ts) {
-                    return write_string(
-                        ret_ptr,
-                        "{\"success\":true,\"message_id\":null,\"error\":null}",
-                    );
+                    return "{\"success\":true,\"message_id\":null,\"error\":null}".to_string();
                 }
                 message_id = Some(stored_id.clone());
             }
         }
     }
 
+    // A host retry of a create that already went through returns the first
+    // message instead of posting a duplicate. Edits are idempotent already.
+    let nonce_key = match (&nonce, &message_id) {
+        (Some(nonce), None) => Some(send_nonce_key(&conversation_id, nonce)),
+        _ => None,
+    };
+    let resume = nonce_key.as_deref().and_then(load_sent_nonce);
+    if let Some(SentNonce {
+        message_id: prior_id,
+        chunks_sent: None,
+    }) = &resume
+    {
+        let response = serde_json::json!({
+            "success": true,
+            "message_id": prior_id,
+            "error": null,
+            "deduplicated": true
+        });
+        return response.to_string();
+    }
+    let discord_nonce = nonce.as_deref().map(discord_nonce);
+    let create_nonce = if message_id.is_none() {
+        discord_nonce.as_deref()
+    } else {
+        None
+    };
+
+    if text.trim().is_empty() && embeds.is_none() && components.is_none() {
+        return "{\"success\":false,\"error\":\"empty message\"}".to_string();
+    }
+
     let app_id = match get_application_id() {
         Some(id) => id,
-        None => {
-            return write_string(
-                ret_ptr,
-                "{\"success\":false,\"error\":\"missing application id\"}",
-            )
-        }
+        None => return "{\"success\":false,\"error\":\"missing application id\"}".to_string(),
+    };
+
+    // Components V2 carry the text in a Text Display, which has its own limit
+    let chunks = if components.is_some() {
+        vec![text.clone()]
+    } else {
+        split_message(&text, MAX_MESSAGE_CHARS)
     };
 
     if let Some(token) = load_interaction_token(&conversation_id) {
+        let create_url = format!(
+            "https://discord.com/api/v10/webhooks/{}/{}?wait=true",
+            app_id, token
+        );
         let url = if let Some(ref msg_id) = message_id {
             format!(
                 "https://discord.com/api/v10/webhooks/{}/{}/messages/{}",
                 app_id, token, msg_id
             )
         } else {
-            format!(
-                "https://discord.com/api/v10/webhooks/{}/{}?wait=true",
-                app_id, token
-            )
+            create_url.clone()
         };
-        let mut payload = serde_json::json!({ "content": text });
-        if ephemeral {
-            if let Value::Object(map) = &mut payload {
-                map.insert("flags".to_string(), Value::Number(64.into()));
-            }
-        }
-        let body = payload.to_string();
+        // Webhook executions take no nonce; retries are deduped via storage only.
         let headers = vec![("Content-Type".to_string(), "application/json".to_string())];
-        if let Some(resp) = http_post(&url, &body, &headers) {
-            let success = resp.status >= 200 && resp.status < 300;
-            let msg_id = extract_message_id(&resp.body);
-            if success {
-                record_sent();
-                if let (Some(status), Some(key), Some(id)) =
-                    (tool_status, tool_key.as_deref(), msg_id.as_deref())
-                {
-                    match status {
-                        ToolStatus::Running => write_tool_state(key, "running", id),
-                        ToolStatus::Completed | ToolStatus::Failed => {
-                            write_tool_state(key, "done", id)
-                        }
-                    }
-                }
-            }
-            let response = serde_json::json!({
-                "success": success,
-                "message_id": msg_id,
-                "error": if success { Value::Null } else { Value::String(resp.body) }
-            });
-            return write_string(ret_ptr, &response.to_string());
+        let sent = post_chunks(
+            &chunks,
+            resume.as_ref(),
+            &url,
+            &create_url,
+            |index, chunk| {
+                let embeds = if index + 1 == chunks.len() {
+                    embeds
+                } else {
+                    None
+                };
+                message_payload(chunk, ephemeral, None, components, embeds, allowed_mentions)
+                    .to_string()
+            },
+            |url, body| send_with_retry(url, body, &headers),
+        );
+        if let Some(outcome) = sent {
+            let response = send_result(
+                outcome,
+                &conversation_id,
+                tool_status,
+                tool_key.as_deref(),
+                nonce_key.as_deref(),
+            );
+            return response;
         }
     }
 
     if ephemeral {
-        return write_string(
-            ret_ptr,
-            "{\"success\":false,\"error\":\"ephemeral response requires interaction token\"}",
-        );
+        return "{\"success\":false,\"error\":\"ephemeral response requires interaction token\"}"
+            .to_string();
     }
 
     if let Some(bot_token) = get_bot_token() {
-        let channel_id = channel_id_override
+        let recipient = send_metadata
+            .recipient_id
             .clone()
-            .unwrap_or_else(|| conversation_id.clone());
+            .or_else(|| conversation_id.strip_prefix("user:").map(str::to_string));
+        let channel_id = match (&channel_id_override, recipient) {
+            (Some(channel_id), _) => channel_id.clone(),
+            (None, Some(user_id)) => match open_dm_channel(&bot_token, &user_id) {
+                Ok(channel_id) => channel_id,
+                Err(err) => {
+                    let response = serde_json::json!({ "success": false, "error": err });
+                    return response.to_string();
+                }
+            },
+            (None, None) => conversation_channel_id(&conversation_id).to_string(),
+        };
+        let create_url = format!(
+            "https://discord.com/api/v10/channels/{}/messages",
+            channel_id
+        );
         let url = if let Some(ref msg_id) = message_id {
             format!(
                 "https://discord.com/api/v10/channels/{}/messages/{}",
                 channel_id, msg_id
             )
         } else {
-            format!(
-                "https://discord.com/api/v10/channels/{}/messages",
-                channel_id
-            )
+            create_url.clone()
         };
-        let body = serde_json::json!({ "content": text }).to_string();
         let headers = vec![
             ("Content-Type".to_string(), "application/json".to_string()),
             ("Authorization".to_string(), format!("Bot {}", bot_token)),
         ];
-        if let Some(resp) = http_post(&url, &body, &headers) {
-            let success = resp.status >= 200 && resp.status < 300;
-            let msg_id = extract_message_id(&resp.body);
-            if success {
-                record_sent();
-                if let (Some(status), Some(key), Some(id)) =
-                    (tool_status, tool_key.as_deref(), msg_id.as_deref())
-                {
-                    match status {
-                        ToolStatus::Running => write_tool_state(key, "running", id),
-                        ToolStatus::Completed | ToolStatus::Failed => {
-                            write_tool_state(key, "done", id)
-                        }
-                    }
-                }
-            }
-            let response = serde_json::json!({
-                "success": success,
-                "message_id": msg_id,
-                "error": if success { Value::Null } else { Value::String(resp.body) }
-            });
-            return write_string(ret_ptr, &response.to_string());
+        let sent = post_chunks(
+            &chunks,
+            resume.as_ref(),
+            &url,
+            &create_url,
+            |index, chunk| {
+                let nonce = if index == 0 { create_nonce } else { None };
+                let embeds = if index + 1 == chunks.len() {
+                    embeds
+                } else {
+                    None
+                };
+                message_payload(chunk, false, nonce, components, embeds, allowed_mentions)
+                    .to_string()
+            },
+            |url, body| send_with_retry(url, body, &headers),
+        );
+        if let Some(outcome) = sent {
+            let response = send_result(
+                outcome,
+                &conversation_id,
+                tool_status,
+                tool_key.as_deref(),
+                nonce_key.as_deref(),
+            );
+            return response;
         }
     }
 
     if let Some((access_token, token_type, expired)) = load_oauth_token() {
         if expired {
-            return write_string(
-                ret_ptr,
-                "{\"success\":false,\"error\":\"oauth token expired\"}",
-            );
+            return "{\"success\":false,\"error\":\"oauth token expired\"}".to_string();
         }
         let channel_id = channel_id_override
             .clone()
-            .unwrap_or_else(|| conversation_id.clone());
+            .unwrap_or_else(|| conversation_channel_id(&conversation_id).to_string());
+        let create_url = format!(
+            "https://discord.com/api/v10/channels/{}/messages",
+            channel_id
+        );
         let url = if let Some(ref msg_id) = message_id {
             format!(
                 "https://discord.com/api/v10/channels/{}/messages/{}",
                 channel_id, msg_id
             )
         } else {
-            format!(
-                "https://discord.com/api/v10/channels/{}/messages",
-                channel_id
-            )
+            create_url.clone()
         };
-        let body = serde_json::json!({ "content": text }).to_string();
         let headers = vec![
             ("Content-Type".to_string(), "application/json".to_string()),
             (
@@ -1079,115 +2388,642 @@ pub extern "C" fn channel_send(req_ptr: i32, req_len: i32, ret_ptr: i32) -> i32
                 format!("{} {}", token_type, access_token),
             ),
         ];
-        if let Some(resp) = http_post(&url, &body, &headers) {
-            let success = resp.status >= 200 && resp.status < 300;
-            let msg_id = extract_message_id(&resp.body);
-            if success {
-                record_sent();
-                if let (Some(status), Some(key), Some(id)) =
-                    (tool_status, tool_key.as_deref(), msg_id.as_deref())
-                {
-                    match status {
-                        ToolStatus::Running => write_tool_state(key, "running", id),
-                        ToolStatus::Completed | ToolStatus::Failed => {
-                            write_tool_state(key, "done", id)
-                        }
-                    }
-                }
-            }
-            let response = serde_json::json!({
-                "success": success,
-                "message_id": msg_id,
-                "error": if success { Value::Null } else { Value::String(resp.body) }
-            });
-            return write_string(ret_ptr, &response.to_string());
+        let sent = post_chunks(
+            &chunks,
+            resume.as_ref(),
+            &url,
+            &create_url,
+            |index, chunk| {
+                let nonce = if index == 0 { create_nonce } else { None };
+                let embeds = if index + 1 == chunks.len() {
+                    embeds
+                } else {
+                    None
+                };
+                message_payload(chunk, false, nonce, components, embeds, allowed_mentions)
+                    .to_string()
+            },
+            |url, body| send_with_retry(url, body, &headers),
+        );
+        if let Some(outcome) = sent {
+            let response = send_result(
+                outcome,
+                &conversation_id,
+                tool_status,
+                tool_key.as_deref(),
+                nonce_key.as_deref(),
+            );
+            return response;
         }
     }
 
-    write_string(
-        ret_ptr,
-        "{\"success\":false,\"error\":\"no valid send token\"}",
+    "{\"success\":false,\"error\":\"no valid send token\"}".to_string()
+}
+
+/// DM channel with `user_id`, opened through `POST /users/@me/channels`
+/// (which returns the existing channel if there is one) and cached in storage
+fn open_dm_channel(bot_token: &str, user_id: &str) -> Result<String, String> {
+    if !is_snowflake(user_id) {
+        return Err(format!("recipient '{}' is not a user id", user_id));
+    }
+    let key = format!("discord_dm_channel:{}", user_id);
+    if let Some(channel_id) = storage_get(&key) {
+        return Ok(channel_id);
+    }
+    let headers = vec![
+        ("Content-Type".to_string(), "application/json".to_string()),
+        ("Authorization".to_string(), format!("Bot {}", bot_token)),
+    ];
+    let body = serde_json::json!({ "recipient_id": user_id }).to_string();
+    let resp = send_with_retry(
+        "https://discord.com/api/v10/users/@me/channels",
+        &body,
+        &headers,
     )
+    .ok_or("could not reach discord to open a DM channel")?;
+    if !(200..300).contains(&resp.status) {
+        return Err(format!(
+            "opening DM channel failed with status {}",
+            resp.status
+        ));
+    }
+    let channel_id = serde_json::from_str::<Value>(&resp.body)
+        .ok()
+        .and_then(|v| v.get("id").and_then(Value::as_str).map(str::to_string))
+        .ok_or("DM channel response has no id")?;
+    storage_set(&key, &channel_id);
+    Ok(channel_id)
+}
+
+/// Per-send options read from `metadata_json.discord`
+#[derive(Debug)]
+struct SendMetadata {
+    channel_id: Option<String>,
+    /// User to DM through the bot token (`discord.recipient_id`)
+    recipient_id: Option<String>,
+    ephemeral: bool,
+    /// Components V2 layout, sent with the IS_COMPONENTS_V2 flag
+    components: Option<Vec<Value>>,
+    /// Embed objects, attached to the last chunk of the message
+    embeds: Option<Vec<Value>>,
+    /// Discord `allowed_mentions` object sent with every message
+    allowed_mentions: Value,
+}
+
+impl Default for SendMetadata {
+    fn default() -> Self {
+        SendMetadata {
+            channel_id: None,
+            recipient_id: None,
+            ephemeral: false,
+            components: None,
+            embeds: None,
+            allowed_mentions: default_allowed_mentions(),
+        }
+    }
+}
+
+/// Resolve no mentions at all, so echoed text like `@everyone` cannot ping
+fn default_allowed_mentions() -> Value {
+    serde_json::json!({ "parse": [] })
 }
 
-fn parse_send_metadata(metadata_json: &str) -> (Option<String>, bool) {
+fn parse_send_metadata(metadata_json: &str) -> SendMetadata {
     if metadata_json.trim().is_empty() {
-        return (None, false);
+        return SendMetadata::default();
     }
     let value: Value = match serde_json::from_str(metadata_json) {
         Ok(v) => v,
-        Err(_) => return (None, false),
+        Err(_) => return SendMetadata::default(),
     };
-    let discord = value
-        .get("discord")
-        .and_then(Value::as_object);
+    let discord = value.get("discord").and_then(Value::as_object);
     let channel_id = discord
         .and_then(|d| d.get("channel_id"))
         .and_then(Value::as_str)
         .map(str::to_string);
+    let recipient_id = discord
+        .and_then(|d| d.get("recipient_id"))
+        .and_then(Value::as_str)
+        .map(str::to_string);
     let ephemeral = discord
         .and_then(|d| d.get("ephemeral"))
         .and_then(Value::as_bool)
         .unwrap_or(false);
-    (channel_id, ephemeral)
+    let components =
+        discord
+            .and_then(|d| d.get("components"))
+            .and_then(|c| match validate_components(c) {
+                Ok(items) => Some(items),
+                Err(err) => {
+                    log_error(&format!("ignoring discord.components: {}", err));
+                    None
+                }
+            });
+    let embeds = discord
+        .and_then(|d| d.get("embeds"))
+        .and_then(|e| match validate_embeds(e) {
+            Ok(items) => Some(items),
+            Err(err) => {
+                log_error(&format!("ignoring discord.embeds: {}", err));
+                None
+            }
+        });
+    // Components V2 messages cannot carry embeds
+    let embeds = match (&components, embeds) {
+        (Some(_), Some(_)) => {
+            log_error("ignoring discord.embeds: not allowed with discord.components");
+            None
+        }
+        (_, embeds) => embeds,
+    };
+    let allowed_mentions = match discord.and_then(|d| d.get("allowed_mentions")) {
+        Some(value) if value.is_object() => value.clone(),
+        Some(_) => {
+            log_error("ignoring discord.allowed_mentions: must be an object");
+            default_allowed_mentions()
+        }
+        None => default_allowed_mentions(),
+    };
+    SendMetadata {
+        channel_id,
+        recipient_id,
+        ephemeral,
+        components,
+        embeds,
+        allowed_mentions,
+    }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum ToolStatus {
-    Running,
-    Completed,
-    Failed,
-}
+/// Most embeds Discord accepts on one message
+const MAX_EMBEDS: usize = 10;
 
-const TOOL_STATE_TTL_SECS: u64 = 10;
+/// Embeds must be a non-empty array of at most MAX_EMBEDS objects
+fn validate_embeds(value: &Value) -> Result<Vec<Value>, String> {
+    let items = value.as_array().ok_or("expected an array")?;
+    if items.is_empty() {
+        return Err("array is empty".to_string());
+    }
+    if items.len() > MAX_EMBEDS {
+        return Err(format!(
+            "{} embeds exceed the limit of {}",
+            items.len(),
+            MAX_EMBEDS
+        ));
+    }
+    if !items.iter().all(Value::is_object) {
+        return Err("every embed must be an object".to_string());
+    }
+    Ok(items.clone())
+}
 
-fn parse_tool_status(text: &str) -> Option<(ToolStatus, String)> {
-    let trimmed = text.trim();
-    if let Some(rest) = trimmed.strip_prefix("🔧 Running ") {
-        let tool = extract_tool_name(rest)?;
-        return Some((ToolStatus::Running, tool));
+/// Message flag IS_COMPONENTS_V2
+const MESSAGE_FLAG_COMPONENTS_V2: u64 = 1 << 15;
+/// Most components (including nested ones) a V2 message may carry
+const MAX_V2_COMPONENTS: usize = 40;
+
+/// Minimal shape check for a Components V2 array: non-empty, every item an
+/// object with a numeric `type`, and within Discord's total component limit
+fn validate_components(value: &Value) -> Result<Vec<Value>, String> {
+    let items = value.as_array().ok_or("expected an array")?;
+    if items.is_empty() {
+        return Err("array is empty".to_string());
     }
-    if let Some(rest) = trimmed.strip_prefix("✅ Completed ") {
-        let tool = extract_tool_name(rest)?;
-        return Some((ToolStatus::Completed, tool));
+    fn count(items: &[Value]) -> Result<usize, String> {
+        let mut total = 0;
+        for item in items {
+            if item.get("type").and_then(Value::as_u64).is_none() {
+                return Err("every component needs a numeric type".to_string());
+            }
+            total += 1;
+            if let Some(children) = item.get("components").and_then(Value::as_array) {
+                total += count(children)?;
+            }
+            if let Some(accessory) = item.get("accessory") {
+                total += count(std::slice::from_ref(accessory))?;
+            }
+        }
+        Ok(total)
     }
-    if let Some(rest) = trimmed.strip_prefix("❌ Failed ") {
-        let tool = extract_tool_name(rest)?;
-        return Some((ToolStatus::Failed, tool));
+    let total = count(items)?;
+    if total > MAX_V2_COMPONENTS {
+        return Err(format!(
+            "{} components exceed the limit of {}",
+            total, MAX_V2_COMPONENTS
+        ));
     }
-    None
+    Ok(items.clone())
 }
 
-fn extract_tool_name(rest: &str) -> Option<String> {
-    let rest = rest.trim_start();
-    if rest.is_empty() {
-        return None;
+/// Body of a create/edit message call. With Components V2 the `content`
+/// field is not allowed, so the text becomes a leading Text Display.
+fn message_payload(
+    text: &str,
+    ephemeral: bool,
+    nonce: Option<&str>,
+    components: Option<&[Value]>,
+    embeds: Option<&[Value]>,
+    allowed_mentions: &Value,
+) -> Value {
+    let mut payload = match components {
+        Some(components) => {
+            let mut items = Vec::with_capacity(components.len() + 1);
+            if !text.trim().is_empty() {
+                items.push(serde_json::json!({ "type": 10, "content": text }));
+            }
+            items.extend(components.iter().cloned());
+            serde_json::json!({
+                "components": items,
+                "flags": MESSAGE_FLAG_COMPONENTS_V2
+            })
+        }
+        None => serde_json::json!({ "content": text }),
+    };
+    if let Value::Object(map) = &mut payload {
+        if ephemeral {
+            let flags = map.get("flags").and_then(Value::as_u64).unwrap_or(0) | 64;
+            map.insert("flags".to_string(), Value::Number(flags.into()));
+        }
+        if let Some(embeds) = embeds {
+            map.insert("embeds".to_string(), Value::Array(embeds.to_vec()));
+        }
+        map.insert("allowed_mentions".to_string(), allowed_mentions.clone());
+        if let Some(nonce) = nonce {
+            map.insert("nonce".to_string(), Value::String(nonce.to_string()));
+            map.insert("enforce_nonce".to_string(), Value::Bool(true));
+        }
     }
-    if let Some(stripped) = rest.strip_prefix('`') {
-        let end = stripped.find('`')?;
-        let name = stripped[..end].trim();
-        if name.is_empty() {
-            None
-        } else {
-            Some(name.to_string())
+    payload
+}
+
+/// Discord's limit on `content`, in characters
+const MAX_MESSAGE_CHARS: usize = 2000;
+
+/// Appended to a chunk that ends inside a code block
+const CLOSE_FENCE: &str = "\n```";
+
+/// Split `text` into pieces of at most `max_chars` characters, preferring to
+/// break at a newline, then at whitespace, and only then mid-word. Only the
+/// separator cut on is dropped. A code block cut in two is closed at the end
+/// of one chunk and reopened, with its language, at the start of the next.
+fn split_message(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    let mut reopen = String::new();
+    while reopen.chars().count() + rest.chars().count() > max_chars {
+        let budget = max_chars.saturating_sub(reopen.chars().count()).max(1);
+        let (mut end, mut next) = cut_point(rest, budget);
+        let mut fence = open_fence(&format!("{}{}", reopen, &rest[..end]));
+        if fence.is_some() {
+            let budget = budget.saturating_sub(CLOSE_FENCE.len()).max(1);
+            (end, next) = cut_point(rest, budget);
+            fence = open_fence(&format!("{}{}", reopen, &rest[..end]));
         }
-    } else {
-        let end = rest.find('\n').unwrap_or(rest.len());
-        let name = rest[..end].trim();
-        if name.is_empty() {
-            None
-        } else {
-            Some(name.to_string())
+        let mut chunk = format!("{}{}", reopen, &rest[..end]);
+        reopen.clear();
+        if let Some(opener) = fence {
+            chunk.push_str(CLOSE_FENCE);
+            reopen = format!("{}\n", opener);
         }
+        chunks.push(chunk);
+        rest = &rest[next..];
+    }
+    if !rest.is_empty() || chunks.is_empty() {
+        chunks.push(format!("{}{}", reopen, rest));
     }
+    chunks
 }
 
-fn tool_status_key(conversation_id: &str, tool_name: &str) -> String {
-    format!(
-        "discord_tool_msg:{}:{}",
-        conversation_id,
-        hex_encode(tool_name)
-    )
+/// Where to cut `text` so the first piece has at most `max_chars`
+/// characters: (end of the piece, start of the remainder). The two differ
+/// by the newline or space cut on; a mid-word cut drops nothing.
+fn cut_point(text: &str, max_chars: usize) -> (usize, usize) {
+    let limit = text
+        .char_indices()
+        .nth(max_chars)
+        .map(|(i, _)| i)
+        .unwrap_or(text.len());
+    let window = &text[..limit];
+    let separator = window
+        .rfind('\n')
+        .or_else(|| window.rfind(char::is_whitespace))
+        .filter(|&i| i > 0);
+    match separator {
+        Some(i) => {
+            let width = window[i..].chars().next().map_or(1, char::len_utf8);
+            (i, i + width)
+        }
+        None => (limit, limit),
+    }
+}
+
+/// Opening line (e.g. "```rust") of a code block left open at the end of
+/// `text`
+fn open_fence(text: &str) -> Option<String> {
+    let mut open = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.starts_with("```") {
+            open = match open {
+                Some(_) => None,
+                None => Some(line.to_string()),
+            };
+        }
+    }
+    open
+}
+
+/// Result of posting a message that may span several chunks
+struct ChunkedResponse {
+    /// Response to the last chunk attempted; None if the host gave no answer
+    last: Option<HttpResponse>,
+    /// Id of the last chunk Discord accepted
+    last_sent_id: Option<String>,
+    sent: usize,
+    total: usize,
+}
+
+/// Post `chunks` in order through `post`: the first to `first_url` (a create
+/// or an edit), the rest as new messages to `create_url`. A retry of a send
+/// that stopped partway (`resume`) picks up after the chunks already
+/// delivered. Stops at the first failure. Returns None when the first chunk
+/// attempted got no response at all, so the caller can fall back to the next
+/// send path.
+fn post_chunks(
+    chunks: &[String],
+    resume: Option<&SentNonce>,
+    first_url: &str,
+    create_url: &str,
+    body_for: impl Fn(usize, &str) -> String,
+    mut post: impl FnMut(&str, &str) -> Option<HttpResponse>,
+) -> Option<ChunkedResponse> {
+    let start = resume.and_then(|r| r.chunks_sent).unwrap_or(0);
+    let mut outcome = ChunkedResponse {
+        last: None,
+        last_sent_id: resume.map(|r| r.message_id.clone()),
+        sent: start,
+        total: chunks.len(),
+    };
+    for (index, chunk) in chunks.iter().enumerate().skip(start) {
+        let url = if index == 0 { first_url } else { create_url };
+        let resp = match post(url, &body_for(index, chunk)) {
+            Some(resp) => resp,
+            None if index == start => return None,
+            None => {
+                outcome.last = None;
+                break;
+            }
+        };
+        let ok = resp.status >= 200 && resp.status < 300;
+        if ok {
+            outcome.sent += 1;
+            outcome.last_sent_id = extract_message_id(&resp.body).or(outcome.last_sent_id);
+        }
+        outcome.last = Some(resp);
+        if !ok {
+            break;
+        }
+    }
+    Some(outcome)
+}
+
+/// Record a finished send (stats, in-flight, tool status, nonce) and build
+/// the `channel_send` result JSON
+fn send_result(
+    outcome: ChunkedResponse,
+    conversation_id: &str,
+    tool_status: Option<ToolStatus>,
+    tool_key: Option<&str>,
+    nonce_key: Option<&str>,
+) -> String {
+    let success = outcome.sent >= outcome.total;
+    let msg_id = outcome.last_sent_id;
+    if outcome.sent > 0 && tool_status.is_none() {
+        clear_user_in_flight(conversation_id);
+    }
+    if success {
+        record_sent();
+        if let (Some(status), Some(key), Some(id)) = (tool_status, tool_key, msg_id.as_deref()) {
+            match status {
+                ToolStatus::Running => write_tool_state(key, "running", id),
+                ToolStatus::Completed | ToolStatus::Failed => write_tool_state(key, "done", id),
+            }
+        }
+        if let (Some(key), Some(id)) = (nonce_key, msg_id.as_deref()) {
+            storage_set(key, &sent_nonce_value(id, None, now_ts()));
+        }
+        return serde_json::json!({
+            "success": true,
+            "message_id": msg_id,
+            "error": null
+        })
+        .to_string();
+    }
+    if outcome.sent > 0 {
+        record_sent();
+        // A host retry with the same nonce resumes after the chunks that
+        // already went out instead of posting them again.
+        if let (Some(key), Some(id)) = (nonce_key, msg_id.as_deref()) {
+            storage_set(key, &sent_nonce_value(id, Some(outcome.sent), now_ts()));
+        }
+        let detail = outcome
+            .last
+            .map(|resp| resp.body)
+            .unwrap_or_else(|| "no response".to_string());
+        return serde_json::json!({
+            "success": false,
+            "message_id": msg_id,
+            "error": format!(
+                "partial send: {} of {} chunks sent ({})",
+                outcome.sent, outcome.total, detail
+            ),
+            "chunks_sent": outcome.sent,
+            "chunks_total": outcome.total
+        })
+        .to_string();
+    }
+    let resp = match outcome.last {
+        Some(resp) => resp,
+        None => return "{\"success\":false,\"error\":\"no response\"}".to_string(),
+    };
+    if let Some(retry_after_ms) = rate_limit_retry_ms(&resp) {
+        return serde_json::json!({
+            "success": false,
+            "message_id": null,
+            "error": "rate_limited",
+            "retry_after_ms": retry_after_ms
+        })
+        .to_string();
+    }
+    serde_json::json!({
+        "success": false,
+        "message_id": null,
+        "error": resp.body
+    })
+    .to_string()
+}
+
+/// Longest 429 wait `channel_send` sits out itself before retrying once;
+/// longer ones are returned to the host as `retry_after_ms`
+const MAX_SEND_RETRY_WAIT_MS: u64 = 2000;
+/// Wait assumed when a 429 carries neither `retry_after` nor `Retry-After`
+const DEFAULT_RETRY_AFTER_MS: u64 = 1000;
+
+/// How long Discord asked us to wait, if this is a 429. The JSON body's
+/// `retry_after` (float seconds) is more precise than the header.
+fn rate_limit_retry_ms(resp: &HttpResponse) -> Option<u64> {
+    if resp.status != 429 {
+        return None;
+    }
+    let from_body = serde_json::from_str::<Value>(&resp.body)
+        .ok()
+        .and_then(|v| v.get("retry_after").and_then(Value::as_f64));
+    let from_header =
+        header_value(&resp.headers, "retry-after").and_then(|v| v.trim().parse::<f64>().ok());
+    Some(
+        from_body
+            .or(from_header)
+            .map(|secs| (secs.max(0.0) * 1000.0).ceil() as u64)
+            .unwrap_or(DEFAULT_RETRY_AFTER_MS),
+    )
+}
+
+/// POST a message, waiting out and retrying one short 429
+fn send_with_retry(url: &str, body: &str, headers: &[(String, String)]) -> Option<HttpResponse> {
+    let resp = http_post(url, body, headers)?;
+    match rate_limit_retry_ms(&resp) {
+        Some(wait_ms) if wait_ms <= MAX_SEND_RETRY_WAIT_MS => {
+            log_info(&format!("discord rate limited, retrying in {}ms", wait_ms));
+            std::thread::sleep(Duration::from_millis(wait_ms));
+            http_post(url, body, headers).or(Some(resp))
+        }
+        _ => Some(resp),
+    }
+}
+
+fn send_nonce_key(conversation_id: &str, nonce: &str) -> String {
+    format!(
+        "discord_send_nonce:{}:{}",
+        conversation_id,
+        hex_encode(nonce)
+    )
+}
+
+/// What an earlier send with the same nonce delivered
+#[derive(Debug, PartialEq)]
+struct SentNonce {
+    /// Id of the last message Discord accepted
+    message_id: String,
+    /// Chunks delivered by a send that stopped partway; None once complete
+    chunks_sent: Option<usize>,
+}
+
+/// Stored as `message_id|ts`, or `message_id|ts|chunks_sent` for a partial send
+fn sent_nonce_value(message_id: &str, chunks_sent: Option<usize>, ts: u64) -> String {
+    match chunks_sent {
+        Some(sent) => format!("{}|{}|{}", message_id, ts, sent),
+        None => format!("{}|{}", message_id, ts),
+    }
+}
+
+/// Parse a stored nonce record, or None if malformed or older than the
+/// retry window
+fn parse_sent_nonce(value: &str, now: u64) -> Option<SentNonce> {
+    let mut parts = value.split('|');
+    let message_id = parts.next()?.to_string();
+    let ts = parts.next()?.parse::<u64>().ok()?;
+    if now.saturating_sub(ts) > SEND_NONCE_TTL_SECS {
+        return None;
+    }
+    let chunks_sent = match parts.next() {
+        Some(sent) => Some(sent.parse::<usize>().ok()?),
+        None => None,
+    };
+    Some(SentNonce {
+        message_id,
+        chunks_sent,
+    })
+}
+
+/// Delivery recorded for a nonce that was sent within the retry window
+fn load_sent_nonce(key: &str) -> Option<SentNonce> {
+    let value = storage_get(key)?;
+    let sent = parse_sent_nonce(&value, now_ts());
+    if sent.is_none() {
+        let _ = storage_delete(key);
+    }
+    sent
+}
+
+/// Discord nonces are limited to 25 characters; longer host nonces are
+/// replaced by a stable FNV-1a hash.
+fn discord_nonce(nonce: &str) -> String {
+    if nonce.len() <= 25 {
+        return nonce.to_string();
+    }
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in nonce.as_bytes() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ToolStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+const TOOL_STATE_TTL_SECS: u64 = 10;
+
+fn parse_tool_status(text: &str) -> Option<(ToolStatus, String)> {
+    let trimmed = text.trim();
+    if let Some(rest) = trimmed.strip_prefix("🔧 Running ") {
+        let tool = extract_tool_name(rest)?;
+        return Some((ToolStatus::Running, tool));
+    }
+    if let Some(rest) = trimmed.strip_prefix("✅ Completed ") {
+        let tool = extract_tool_name(rest)?;
+        return Some((ToolStatus::Completed, tool));
+    }
+    if let Some(rest) = trimmed.strip_prefix("❌ Failed ") {
+        let tool = extract_tool_name(rest)?;
+        return Some((ToolStatus::Failed, tool));
+    }
+    None
+}
+
+fn extract_tool_name(rest: &str) -> Option<String> {
+    let rest = rest.trim_start();
+    if rest.is_empty() {
+        return None;
+    }
+    if let Some(stripped) = rest.strip_prefix('`') {
+        let end = stripped.find('`')?;
+        let name = stripped[..end].trim();
+        if name.is_empty() {
+            None
+        } else {
+            Some(name.to_string())
+        }
+    } else {
+        let end = rest.find('\n').unwrap_or(rest.len());
+        let name = rest[..end].trim();
+        if name.is_empty() {
+            None
+        } else {
+            Some(name.to_string())
+        }
+    }
+}
+
+fn tool_status_key(conversation_id: &str, tool_name: &str) -> String {
+    format!(
+        "discord_tool_msg:{}:{}",
+        conversation_id,
+        hex_encode(tool_name)
+    )
 }
 
 fn hex_encode(value: &str) -> String {
@@ -1317,36 +3153,303 @@ fn extract_user_and_roles(payload: &Value) -> (String, Vec<String>) {
     ("unknown".to_string(), roles)
 }
 
+/// Classify an interaction by its `context` field, falling back to `guild_id`
+/// for payloads that predate user-installable apps.
+fn interaction_context(payload: &Value) -> InteractionContext {
+    match payload.get("context").and_then(Value::as_i64) {
+        Some(0) => InteractionContext::Guild,
+        Some(1) => InteractionContext::BotDm,
+        Some(2) => InteractionContext::PrivateChannel,
+        _ if payload.get("guild_id").is_some() => InteractionContext::Guild,
+        _ => InteractionContext::BotDm,
+    }
+}
+
+/// An option value as text: strings as they are, integers, numbers and
+/// booleans in their JSON form
+fn option_value_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
 fn extract_command(payload: &Value) -> (String, Value) {
     let data = payload.get("data").unwrap_or(&Value::Null);
     let name = data.get("name").and_then(Value::as_str).unwrap_or("tark");
     let mut command = Value::Null;
+    let mut text: Option<String> = None;
+    let mut model: Option<String> = None;
+
+    // A subcommand group (type 2) or subcommand (type 1) is the only option
+    // at its level; its own options hold the next level down.
+    let mut options = data
+        .get("options")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    let mut path: Vec<String> = Vec::new();
+    while let [only] = options.as_slice() {
+        let kind = only.get("type").and_then(Value::as_i64);
+        if !matches!(kind, Some(1) | Some(2)) {
+            break;
+        }
+        path.push(
+            only.get("name")
+                .and_then(Value::as_str)
+                .unwrap_or("")
+                .to_string(),
+        );
+        options = only
+            .get("options")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+    }
 
-    let options = data.get("options").and_then(Value::as_array).cloned();
-    if let Some(options) = options {
-        for opt in options {
-            if let Some(opt_name) = opt.get("name").and_then(Value::as_str) {
-                if let Some(value) = opt.get("value").and_then(Value::as_str) {
-                    if opt_name == "prompt" {
-                        return (value.to_string(), Value::Null);
-                    }
-                    if opt_name == "command" {
-                        return (format!("/tark {}", value), Value::Null);
+    let mut typed_options = serde_json::Map::new();
+    let mut args: Vec<String> = Vec::new();
+    for opt in options {
+        if let Some(opt_name) = opt.get("name").and_then(Value::as_str) {
+            if let Some(value_text) = opt.get("value").and_then(option_value_string) {
+                let value = value_text.as_str();
+                typed_options.insert(opt_name.to_string(), opt["value"].clone());
+                if opt_name == "public" {
+                    continue;
+                }
+                if opt_name == "model" {
+                    let value = value.trim();
+                    if !value.is_empty() {
+                        model = Some(value.to_string());
                     }
-                    command = serde_json::json!({
-                        "name": opt_name,
-                        "value": value
-                    });
+                    continue;
+                }
+                if text.is_some() {
+                    continue;
                 }
+                if opt_name == "prompt" {
+                    text = Some(value.to_string());
+                    command = Value::Null;
+                    continue;
+                }
+                if opt_name == "command" {
+                    text = Some(format!("/tark {}", value));
+                    command = Value::Null;
+                    continue;
+                }
+                args.push(value.to_string());
+                command = serde_json::json!({
+                    "name": opt_name,
+                    "value": value
+                });
             }
         }
     }
 
-    if name == "tark" {
-        ("/tark status".to_string(), command)
-    } else {
-        (format!("/tark {}", name), command)
+    if !path.is_empty() {
+        if !command.is_object() {
+            command = serde_json::json!({});
+        }
+        command["subcommand"] = Value::String(path.join(" "));
+        command["options"] = Value::Object(typed_options);
     }
+
+    // Model routing is left to the host; we only pass the choice along.
+    if let Some(model) = model {
+        match &mut command {
+            Value::Object(map) => {
+                map.insert("model".to_string(), Value::String(model));
+            }
+            _ => command = serde_json::json!({ "model": model }),
+        }
+    }
+
+    let text = text.unwrap_or_else(|| {
+        if !path.is_empty() {
+            let words: Vec<&str> = std::iter::once(name)
+                .filter(|n| *n != "tark")
+                .chain(path.iter().map(String::as_str))
+                .chain(args.iter().map(String::as_str))
+                .collect();
+            format!("/tark {}", words.join(" "))
+        } else if name == "tark" {
+            "/tark status".to_string()
+        } else {
+            format!("/tark {}", name)
+        }
+    });
+    (text, command)
+}
+
+/// A button click or select menu choice from a type 3 interaction
+#[derive(Debug, PartialEq)]
+struct ComponentAction {
+    custom_id: String,
+    component_type: i64,
+    values: Vec<String>,
+}
+
+impl ComponentAction {
+    /// Inbound text for the action: `/tark component <custom_id>`, followed
+    /// by the chosen values for select menus
+    fn text(&self) -> String {
+        if self.values.is_empty() {
+            format!("/tark component {}", self.custom_id)
+        } else {
+            format!(
+                "/tark component {} {}",
+                self.custom_id,
+                self.values.join(",")
+            )
+        }
+    }
+}
+
+fn extract_component(payload: &Value) -> ComponentAction {
+    let data = payload.get("data").unwrap_or(&Value::Null);
+    ComponentAction {
+        custom_id: data
+            .get("custom_id")
+            .and_then(Value::as_str)
+            .unwrap_or("")
+            .to_string(),
+        component_type: data
+            .get("component_type")
+            .and_then(Value::as_i64)
+            .unwrap_or(0),
+        values: data
+            .get("values")
+            .and_then(Value::as_array)
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+/// The text inputs of a type 5 (modal submit) interaction
+#[derive(Debug, PartialEq)]
+struct ModalSubmit {
+    custom_id: String,
+    fields: Vec<(String, String)>,
+}
+
+impl ModalSubmit {
+    /// A single field is sent as its value; several become one
+    /// `custom_id: value` line each
+    fn text(&self) -> String {
+        match self.fields.as_slice() {
+            [(_, value)] => value.clone(),
+            fields => fields
+                .iter()
+                .map(|(id, value)| format!("{}: {}", id, value))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let fields: serde_json::Map<String, Value> = self
+            .fields
+            .iter()
+            .map(|(id, value)| (id.clone(), Value::String(value.clone())))
+            .collect();
+        serde_json::json!({ "custom_id": self.custom_id, "fields": fields })
+    }
+}
+
+/// Collect `custom_id`/`value` pairs from the modal's action rows
+/// (`components[].components[]`) and label wrappers (`components[].component`)
+fn extract_modal(payload: &Value) -> ModalSubmit {
+    let data = payload.get("data").unwrap_or(&Value::Null);
+    let rows = data
+        .get("components")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let mut fields = Vec::new();
+    for row in rows {
+        let inputs = row
+            .get("components")
+            .and_then(Value::as_array)
+            .map(|inputs| inputs.iter().collect::<Vec<_>>())
+            .or_else(|| row.get("component").map(|input| vec![input]))
+            .unwrap_or_default();
+        for input in inputs {
+            let id = input.get("custom_id").and_then(Value::as_str);
+            let value = input.get("value").and_then(Value::as_str);
+            if let (Some(id), Some(value)) = (id, value) {
+                fields.push((id.to_string(), value.to_string()));
+            }
+        }
+    }
+    ModalSubmit {
+        custom_id: data
+            .get("custom_id")
+            .and_then(Value::as_str)
+            .unwrap_or("")
+            .to_string(),
+        fields,
+    }
+}
+
+/// `DISCORD_PREMIUM_SKU`: SKU id a user must be entitled to before their
+/// interactions are served (default: no gating)
+fn premium_sku() -> Option<String> {
+    env_get("DISCORD_PREMIUM_SKU")
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// The interaction's `entitlements`, reduced to what gating and the host need
+fn extract_entitlements(payload: &Value) -> Vec<Value> {
+    payload
+        .get("entitlements")
+        .and_then(Value::as_array)
+        .map(|items| {
+            items
+                .iter()
+                .filter_map(|item| {
+                    let sku_id = item.get("sku_id").and_then(Value::as_str)?;
+                    Some(serde_json::json!({
+                        "id": item.get("id").cloned().unwrap_or(Value::Null),
+                        "sku_id": sku_id,
+                        "type": item.get("type").cloned().unwrap_or(Value::Null),
+                        "ends_at": item.get("ends_at").cloned().unwrap_or(Value::Null),
+                        "consumed": item.get("consumed").and_then(Value::as_bool).unwrap_or(false)
+                    }))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Whether the entitlements include `sku_id`. Discord only sends the active
+/// entitlements with an interaction, so no date check is needed.
+fn has_entitlement(entitlements: &[Value], sku_id: &str) -> bool {
+    entitlements
+        .iter()
+        .any(|e| e.get("sku_id").and_then(Value::as_str) == Some(sku_id))
+}
+
+/// Ephemeral reply with Discord's premium purchase button for `sku_id`
+fn premium_required_response(sku_id: &str) -> Value {
+    serde_json::json!({
+        "type": 4,
+        "data": {
+            "content": "This command requires a premium subscription.",
+            "flags": 64,
+            "components": [{
+                "type": 1,
+                "components": [{ "type": 2, "style": 6, "sku_id": sku_id }]
+            }]
+        }
+    })
 }
 
 fn normalize_attachment(item: &Value) -> Option<Value> {
@@ -1360,6 +3463,7 @@ fn normalize_attachment(item: &Value) -> Option<Value> {
     let size = item.get("size").and_then(Value::as_u64);
     let width = item.get("width").and_then(Value::as_u64);
     let height = item.get("height").and_then(Value::as_u64);
+    let duration_secs = item.get("duration_secs").and_then(Value::as_f64);
     Some(serde_json::json!({
         "id": id,
         "filename": filename,
@@ -1367,7 +3471,8 @@ fn normalize_attachment(item: &Value) -> Option<Value> {
         "content_type": content_type,
         "size": size,
         "width": width,
-        "height": height
+        "height": height,
+        "duration_secs": duration_secs
     }))
 }
 
@@ -1386,7 +3491,10 @@ fn extract_attachments_from_message(data: &Value) -> Vec<Value> {
 fn extract_attachments_from_interaction(data: &Value) -> Vec<Value> {
     let mut attachments = Vec::new();
     let resolved = data.get("data").and_then(|d| d.get("resolved"));
-    if let Some(map) = resolved.and_then(|r| r.get("attachments")).and_then(Value::as_object) {
+    if let Some(map) = resolved
+        .and_then(|r| r.get("attachments"))
+        .and_then(Value::as_object)
+    {
         for (_id, item) in map {
             if let Some(normalized) = normalize_attachment(item) {
                 attachments.push(normalized);
@@ -1401,19 +3509,75 @@ fn extract_message_id(body: &str) -> Option<String> {
     value.get("id").and_then(Value::as_str).map(str::to_string)
 }
 
+/// Close the socket. The session is kept, so the next connect RESUMEs.
 fn reset_gateway(state: &mut GatewayState) {
+    save_gateway_session(state);
     if let Some(handle) = state.handle.take() {
         ws_close(handle);
     }
+    state.identify_pending = false;
     state.heartbeat_interval_ms = None;
     state.last_heartbeat = None;
     state.last_heartbeat_ack = true;
-    state.seq = None;
+    state.missed_heartbeat_acks = 0;
     state.connected = false;
     set_gateway_connected(false);
 }
 
-fn handle_gateway_payload(state: &mut GatewayState, payload: &Value, token: &str) -> Vec<InboundMessage> {
+/// Drop the session so the next connect starts over with IDENTIFY
+fn forget_gateway_session(state: &mut GatewayState) {
+    state.seq = None;
+    state.session_id = None;
+    state.resume_gateway_url = None;
+    storage_delete(GATEWAY_SESSION_KEY);
+}
+
+/// Persist the session so a restarted plugin can still RESUME. Called on
+/// READY, heartbeats, resets and stop rather than on every sequence number,
+/// so a busy guild does not cost a storage write per event.
+fn save_gateway_session(state: &GatewayState) {
+    let (Some(session_id), Some(seq)) = (&state.session_id, state.seq) else {
+        return;
+    };
+    let stored = StoredGatewaySession {
+        session_id: session_id.clone(),
+        seq,
+        resume_gateway_url: state.resume_gateway_url.clone(),
+    };
+    if let Ok(json) = serde_json::to_string(&stored) {
+        storage_set(GATEWAY_SESSION_KEY, &json);
+    }
+}
+
+/// Pick up a session persisted by an earlier run, if there is none in memory
+fn load_gateway_session(state: &mut GatewayState) {
+    if state.session_id.is_some() {
+        return;
+    }
+    let stored = storage_get(GATEWAY_SESSION_KEY)
+        .and_then(|raw| serde_json::from_str::<StoredGatewaySession>(&raw).ok());
+    if let Some(stored) = stored {
+        log_info("gateway session restored from storage, will resume");
+        state.session_id = Some(stored.session_id);
+        state.seq = Some(stored.seq);
+        state.resume_gateway_url = stored.resume_gateway_url;
+    }
+}
+
+/// Gateway URL to connect to: the session's resume URL when there is a
+/// session to resume, the default one otherwise
+fn gateway_connect_url(state: &GatewayState) -> String {
+    match (&state.session_id, &state.resume_gateway_url) {
+        (Some(_), Some(url)) => format!("{}/?v=10&encoding=json", url.trim_end_matches('/')),
+        _ => DISCORD_GATEWAY_URL.to_string(),
+    }
+}
+
+fn handle_gateway_payload(
+    state: &mut GatewayState,
+    payload: &Value,
+    token: &str,
+) -> Vec<InboundMessage> {
     let op = payload.get("op").and_then(Value::as_i64).unwrap_or(0);
     if let Some(seq) = payload.get("s").and_then(Value::as_i64) {
         state.seq = Some(seq);
@@ -1426,43 +3590,95 @@ fn handle_gateway_payload(state: &mut GatewayState, payload: &Value, token: &str
                 .and_then(|d| d.get("heartbeat_interval"))
                 .and_then(Value::as_u64)
                 .unwrap_or(45000);
+            let interval_ms = heartbeat_interval_ms(interval_ms);
             state.heartbeat_interval_ms = Some(interval_ms);
             state.last_heartbeat_ack = true;
             state.last_heartbeat = Some(Instant::now());
             log_info(&format!("gateway HELLO (heartbeat={}ms)", interval_ms));
-            let identify = serde_json::json!({
-                "op": 2,
-                "d": {
-                    "token": token,
-                    "intents": DISCORD_INTENTS_DM_ONLY,
-                    "properties": {
-                        "$os": "linux",
-                        "$browser": "tark",
-                        "$device": "tark"
+            if let (Some(session_id), Some(seq)) = (&state.session_id, state.seq) {
+                let resume = serde_json::json!({
+                    "op": 6,
+                    "d": {
+                        "token": token,
+                        "session_id": session_id,
+                        "seq": seq
                     }
+                });
+                if let Some(handle) = state.handle {
+                    let _ = ws_send(handle, &resume.to_string());
+                    log_info("gateway RESUME sent");
                 }
+                return Vec::new();
+            }
+            state.identify_pending = true;
+            send_identify(state, token);
+        }
+        1 => {
+            // Discord wants a heartbeat right away
+            let heartbeat = serde_json::json!({
+                "op": 1,
+                "d": state.seq
             });
             if let Some(handle) = state.handle {
-                let _ = ws_send(handle, &identify.to_string());
+                let _ = ws_send(handle, &heartbeat.to_string());
+                state.last_heartbeat = Some(Instant::now());
+                state.last_heartbeat_ack = false;
+                log_info("gateway HEARTBEAT sent on request");
             }
+            save_gateway_session(state);
         }
         11 => {
             state.last_heartbeat_ack = true;
+            state.missed_heartbeat_acks = 0;
             log_info("gateway HEARTBEAT_ACK");
         }
-        7 | 9 => {
-            log_info("gateway RECONNECT requested");
+        7 => {
+            log_info("gateway RECONNECT requested, will resume");
             reset_gateway(state);
         }
+        9 => {
+            // `d` says whether the session can still be resumed
+            if payload.get("d").and_then(Value::as_bool).unwrap_or(false) {
+                log_info("gateway INVALID_SESSION (resumable)");
+                reset_gateway(state);
+            } else {
+                let wait = invalid_session_wait_secs();
+                log_info(&format!(
+                    "gateway INVALID_SESSION, will identify again in {}s",
+                    wait
+                ));
+                forget_gateway_session(state);
+                let until = now_ts() + wait;
+                state.identify_not_before = Some(until);
+                state.identify_pending = true;
+                set_identify_throttled(Some(until));
+            }
+        }
         0 => {
             let event_type = payload.get("t").and_then(Value::as_str).unwrap_or("");
             let data = payload.get("d").unwrap_or(&Value::Null);
             match event_type {
                 "READY" => {
                     state.connected = true;
+                    state.connect_attempts = 0;
+                    state.session_id = data
+                        .get("session_id")
+                        .and_then(Value::as_str)
+                        .map(str::to_string);
+                    state.resume_gateway_url = data
+                        .get("resume_gateway_url")
+                        .and_then(Value::as_str)
+                        .map(str::to_string);
+                    save_gateway_session(state);
                     set_gateway_connected(true);
                     log_info("gateway READY");
                 }
+                "RESUMED" => {
+                    state.connected = true;
+                    state.connect_attempts = 0;
+                    set_gateway_connected(true);
+                    log_info("gateway RESUMED");
+                }
                 "MESSAGE_CREATE" => return parse_gateway_message_create(data),
                 "INTERACTION_CREATE" => return parse_gateway_interaction_create(data),
                 _ => {}
@@ -1474,6 +3690,107 @@ fn handle_gateway_payload(state: &mut GatewayState, payload: &Value, token: &str
     Vec::new()
 }
 
+/// Seconds until another IDENTIFY fits in the rate limit window, or `None`
+/// if one may be sent now. Drops timestamps that left the window.
+fn identify_wait_secs(sent_at: &mut VecDeque<u64>, now: u64) -> Option<u64> {
+    while sent_at
+        .front()
+        .is_some_and(|&t| now.saturating_sub(t) >= IDENTIFY_WINDOW_SECS)
+    {
+        sent_at.pop_front();
+    }
+    if sent_at.len() < IDENTIFY_LIMIT {
+        return None;
+    }
+    sent_at
+        .front()
+        .map(|&oldest| oldest + IDENTIFY_WINDOW_SECS - now)
+}
+
+/// Wait before re-IDENTIFYing after INVALID_SESSION, spread over
+/// INVALID_SESSION_MIN_WAIT_SECS..=INVALID_SESSION_MAX_WAIT_SECS so restarted
+/// clients do not all come back at once
+fn invalid_session_wait_secs() -> u64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    let span = INVALID_SESSION_MAX_WAIT_SECS - INVALID_SESSION_MIN_WAIT_SECS + 1;
+    INVALID_SESSION_MIN_WAIT_SECS + nanos % span
+}
+
+/// Send the pending IDENTIFY, unless it must wait out an INVALID_SESSION or
+/// would exceed IDENTIFY_LIMIT per IDENTIFY_WINDOW_SECS; then it stays
+/// pending for a later `gateway_poll`.
+fn send_identify(state: &mut GatewayState, token: &str) {
+    let Some(handle) = state.handle else {
+        return;
+    };
+    let now = now_ts();
+    if let Some(until) = state.identify_not_before {
+        if now < until {
+            return;
+        }
+        state.identify_not_before = None;
+    }
+    if let Some(wait) = identify_wait_secs(&mut state.identify_sent_at, now) {
+        let first = set_identify_throttled(Some(now + wait));
+        if first {
+            log_error(&format!("gateway IDENTIFY throttled for {}s", wait));
+        }
+        return;
+    }
+    let identify = serde_json::json!({
+        "op": 2,
+        "d": {
+            "token": token,
+            "intents": gateway_intents(&private_mode()),
+            "properties": {
+                "$os": "linux",
+                "$browser": "tark",
+                "$device": "tark"
+            }
+        }
+    });
+    let _ = ws_send(handle, &identify.to_string());
+    state.identify_sent_at.push_back(now);
+    state.identify_pending = false;
+    set_identify_throttled(None);
+}
+
+/// Open the gateway websocket if it is not open yet
+fn ensure_gateway_socket(state: &mut GatewayState) -> Result<(), String> {
+    if state.handle.is_some() {
+        return Ok(());
+    }
+    if state.gave_up {
+        return Err("gateway reconnect limit reached".to_string());
+    }
+    if let Some(max) = max_gateway_reconnects() {
+        // The first connect is not a reconnect
+        if state.connect_attempts > max {
+            let error = format!(
+                "gateway gave up after {} reconnects without READY; \
+                 call channel_start or re-auth to retry",
+                max
+            );
+            log_error(&error);
+            state.gave_up = true;
+            set_gateway_error(Some(error.clone()));
+            return Err(error);
+        }
+    }
+    state.connect_attempts += 1;
+    let handle = ws_connect(&gateway_connect_url(state), &[])?;
+    state.handle = Some(handle);
+    state.connected = false;
+    set_gateway_connected(false);
+    if !LOGGED_GATEWAY_CONNECT.swap(true, Ordering::SeqCst) {
+        log_info("gateway connected");
+    }
+    Ok(())
+}
+
 fn gateway_poll() -> Vec<InboundMessage> {
     let token = match get_bot_token() {
         Some(t) => t,
@@ -1490,21 +3807,17 @@ fn gateway_poll() -> Vec<InboundMessage> {
         Err(_) => return Vec::new(),
     };
 
+    if state.gave_up {
+        return Vec::new();
+    }
     if state.handle.is_none() {
-        match ws_connect(DISCORD_GATEWAY_URL, &[]) {
-            Ok(handle) => {
-                state.handle = Some(handle);
-                state.connected = false;
-                set_gateway_connected(false);
-                if !LOGGED_GATEWAY_CONNECT.swap(true, Ordering::SeqCst) {
-                    log_info("gateway connected");
-                }
-            }
-            Err(err) => {
-                log_error(&format!("gateway connect failed: {}", err));
-                return Vec::new();
-            }
+        load_gateway_session(&mut state);
+    }
+    if let Err(err) = ensure_gateway_socket(&mut state) {
+        if !state.gave_up {
+            log_error(&format!("gateway connect failed: {}", err));
         }
+        return Vec::new();
     }
 
     let mut messages = Vec::new();
@@ -1545,13 +3858,26 @@ fn gateway_poll() -> Vec<InboundMessage> {
         break;
     }
 
+    if state.identify_pending {
+        send_identify(&mut state, &token);
+    }
+
     if let Some(interval_ms) = state.heartbeat_interval_ms {
         if let Some(last) = state.last_heartbeat {
             if last.elapsed() >= Duration::from_millis(interval_ms) {
                 if !state.last_heartbeat_ack {
-                    log_error("gateway HEARTBEAT missing ACK; resetting");
-                    reset_gateway(&mut state);
-                    return messages;
+                    state.missed_heartbeat_acks += 1;
+                    record_missed_heartbeat_ack();
+                    let max_missed = max_missed_heartbeat_acks();
+                    if state.missed_heartbeat_acks >= max_missed {
+                        log_error("gateway HEARTBEAT missing ACK; resetting");
+                        reset_gateway(&mut state);
+                        return messages;
+                    }
+                    log_error(&format!(
+                        "gateway HEARTBEAT missing ACK ({}/{})",
+                        state.missed_heartbeat_acks, max_missed
+                    ));
                 }
                 let heartbeat = serde_json::json!({
                     "op": 1,
@@ -1563,6 +3889,7 @@ fn gateway_poll() -> Vec<InboundMessage> {
                     state.last_heartbeat_ack = false;
                     log_info("gateway HEARTBEAT sent");
                 }
+                save_gateway_session(&state);
             }
         }
     }
@@ -1570,6 +3897,48 @@ fn gateway_poll() -> Vec<InboundMessage> {
     messages
 }
 
+/// Queue freshly received messages behind any deferred ones and release at
+/// most one per user, skipping users whose previous message is still in flight.
+fn schedule_fair(incoming: Vec<InboundMessage>) -> Vec<InboundMessage> {
+    let mut guard = match FAIR_QUEUE.lock() {
+        Ok(q) => q,
+        Err(_) => return incoming,
+    };
+    let queue = &mut *guard;
+    let now = now_ts();
+    queue
+        .in_flight
+        .retain(|_, (_, started)| now.saturating_sub(*started) < USER_IN_FLIGHT_TTL_SECS);
+    queue.pending.extend(incoming);
+
+    let mut ready = Vec::new();
+    let mut deferred = VecDeque::new();
+    let mut released_users = HashSet::new();
+    while let Some(msg) = queue.pending.pop_front() {
+        if queue.in_flight.contains_key(&msg.user_id) || !released_users.insert(msg.user_id.clone())
+        {
+            deferred.push_back(msg);
+            continue;
+        }
+        ready.push(msg);
+    }
+    for msg in &ready {
+        queue
+            .in_flight
+            .insert(msg.user_id.clone(), (msg.conversation_id.clone(), now));
+    }
+    queue.pending = deferred;
+    ready
+}
+
+fn clear_user_in_flight(conversation_id: &str) {
+    if let Ok(mut queue) = FAIR_QUEUE.lock() {
+        queue
+            .in_flight
+            .retain(|_, (conversation, _)| conversation != conversation_id);
+    }
+}
+
 fn parse_gateway_event(payload: &Value) -> Vec<InboundMessage> {
     let event_type = payload.get("t").and_then(Value::as_str).unwrap_or("");
     let data = payload.get("d").unwrap_or(&Value::Null);
@@ -1580,14 +3949,65 @@ fn parse_gateway_event(payload: &Value) -> Vec<InboundMessage> {
     }
 }
 
-fn parse_gateway_message_create(data: &Value) -> Vec<InboundMessage> {
-    if data.get("guild_id").is_some() {
-        return Vec::new();
+/// Message flag IS_VOICE_MESSAGE
+const MESSAGE_FLAG_VOICE: u64 = 1 << 13;
+
+fn is_voice_message(data: &Value) -> bool {
+    data.get("flags")
+        .and_then(Value::as_u64)
+        .map(|flags| flags & MESSAGE_FLAG_VOICE != 0)
+        .unwrap_or(false)
+}
+
+fn extract_stickers(data: &Value) -> Vec<Value> {
+    data.get("sticker_items")
+        .and_then(Value::as_array)
+        .map(|items| {
+            items
+                .iter()
+                .map(|item| {
+                    serde_json::json!({
+                        "id": item.get("id").and_then(Value::as_str).unwrap_or(""),
+                        "name": item.get("name").and_then(Value::as_str).unwrap_or("sticker"),
+                        "format_type": item.get("format_type").and_then(Value::as_u64)
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The configured intents, or else the DM-only set plus GUILD_MESSAGES when
+/// some guild channel may be used
+fn gateway_intents(mode: &PrivateMode) -> u64 {
+    if let Some(intents) = configured_intents() {
+        return intents;
     }
+    match mode {
+        PrivateMode::DmOnly => DISCORD_INTENTS_DM_ONLY,
+        _ => DISCORD_INTENTS_DM_ONLY | DISCORD_INTENT_GUILD_MESSAGES,
+    }
+}
 
-    let channel_type = data.get("channel_type").and_then(Value::as_i64).unwrap_or(0);
-    if channel_type != 1 {
-        return Vec::new();
+fn parse_gateway_message_create(data: &Value) -> Vec<InboundMessage> {
+    let guild_id = data.get("guild_id").and_then(Value::as_str);
+    let channel_id = data
+        .get("channel_id")
+        .and_then(Value::as_str)
+        .unwrap_or("unknown")
+        .to_string();
+    if guild_id.is_some() {
+        if !private_mode().allows_channel(&channel_id) {
+            return Vec::new();
+        }
+    } else {
+        let channel_type = data
+            .get("channel_type")
+            .and_then(Value::as_i64)
+            .unwrap_or(0);
+        if channel_type != 1 {
+            return Vec::new();
+        }
     }
 
     let author = match data.get("author") {
@@ -1599,57 +4019,91 @@ fn parse_gateway_message_create(data: &Value) -> Vec<InboundMessage> {
     }
 
     let attachments = extract_attachments_from_message(data);
+    let stickers = extract_stickers(data);
+    let voice_message = is_voice_message(data);
     let content = data
         .get("content")
         .and_then(Value::as_str)
         .unwrap_or("")
         .trim();
-    if content.is_empty() && attachments.is_empty() {
+    let text = if !content.is_empty() {
+        content.to_string()
+    } else if voice_message {
+        "[voice message]".to_string()
+    } else if !stickers.is_empty() {
+        let names: Vec<&str> = stickers
+            .iter()
+            .filter_map(|s| s.get("name").and_then(Value::as_str))
+            .collect();
+        format!("[sticker: {}]", names.join(", "))
+    } else {
+        String::new()
+    };
+    if text.is_empty() && attachments.is_empty() {
         return Vec::new();
     }
 
-    let channel_id = data
-        .get("channel_id")
-        .and_then(Value::as_str)
-        .unwrap_or("unknown")
-        .to_string();
     let user_id = author
         .get("id")
         .and_then(Value::as_str)
         .unwrap_or("unknown")
         .to_string();
+    let roles: Vec<String> = data
+        .get("member")
+        .and_then(|m| m.get("roles"))
+        .and_then(Value::as_array)
+        .map(|roles| {
+            roles
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    if !is_authorized(&user_id, &roles) {
+        return Vec::new();
+    }
+    // Guild channels are shared, so each user gets their own conversation
+    let conversation_id = match guild_id {
+        Some(_) => format!("{}:{}", channel_id, user_id),
+        None => channel_id.clone(),
+    };
 
     let metadata = serde_json::json!({
         "discord": {
             "user_id": user_id.clone(),
             "channel_id": channel_id.clone(),
-            "guild_id": null,
-            "roles": [],
+            "guild_id": guild_id,
+            "roles": roles,
             "interaction_token": "",
             "ephemeral": false,
-            "attachments": attachments
+            "attachments": attachments,
+            "stickers": stickers,
+            "voice_message": voice_message
         }
     });
 
     record_received();
     vec![InboundMessage {
-        conversation_id: channel_id,
+        conversation_id,
         user_id,
-        text: content.to_string(),
+        text,
         metadata_json: metadata.to_string(),
     }]
 }
 
 fn parse_gateway_interaction_create(data: &Value) -> Vec<InboundMessage> {
-    if data.get("guild_id").is_some() {
-        return Vec::new();
-    }
-
+    let context = interaction_context(data);
+    let is_dm = context == InteractionContext::BotDm;
     let channel_id = data
         .get("channel_id")
         .and_then(Value::as_str)
         .unwrap_or("unknown")
         .to_string();
+    if !is_dm && !private_mode().allows_channel(&channel_id) {
+        return Vec::new();
+    }
+
     let interaction_token = data
         .get("token")
         .and_then(Value::as_str)
@@ -1661,22 +4115,43 @@ fn parse_gateway_interaction_create(data: &Value) -> Vec<InboundMessage> {
     }
 
     let (user_id, roles) = extract_user_and_roles(data);
+    if !is_authorized(&user_id, &roles) {
+        return Vec::new();
+    }
+    let entitlements = extract_entitlements(data);
+    if let Some(sku_id) = premium_sku() {
+        if !has_entitlement(&entitlements, &sku_id) {
+            log_info(&format!(
+                "dropping interaction from {}: no entitlement",
+                user_id
+            ));
+            return Vec::new();
+        }
+    }
     let (text, command) = extract_command(data);
-    let conversation_id = channel_id.clone();
+    let guild_id = data.get("guild_id").and_then(Value::as_str);
+    let conversation_id = if is_dm {
+        channel_id.clone()
+    } else {
+        format!("{}:{}", channel_id, user_id)
+    };
     if !interaction_token.is_empty() {
         store_interaction_token(&conversation_id, &interaction_token);
     }
     let attachments = extract_attachments_from_interaction(data);
+    let ephemeral = interaction_ephemeral(data);
 
     let metadata = serde_json::json!({
         "discord": {
             "user_id": user_id.clone(),
             "channel_id": channel_id.clone(),
-            "guild_id": null,
+            "guild_id": guild_id,
+            "context": context.as_str(),
             "roles": roles,
             "interaction_token": interaction_token,
-            "ephemeral": false,
-            "attachments": attachments
+            "ephemeral": ephemeral,
+            "attachments": attachments,
+            "entitlements": entitlements
         },
         "tark_command": command
     });
@@ -1686,10 +4161,455 @@ fn parse_gateway_interaction_create(data: &Value) -> Vec<InboundMessage> {
     }
 
     record_received();
-    vec![InboundMessage {
-        conversation_id,
-        user_id,
+    build_interaction_messages(data, &conversation_id, &user_id, text, metadata)
+}
+
+/// Truthy boolean option (`true` or `"true"`) on an interaction's command
+fn option_flag(data: &Value, name: &str) -> bool {
+    option_bool(data, name).unwrap_or(false)
+}
+
+/// Boolean command option, or None when the user did not pass it
+fn option_bool(data: &Value, name: &str) -> Option<bool> {
+    data.get("data")
+        .and_then(|d| d.get("options"))
+        .and_then(Value::as_array)
+        .and_then(|opts| {
+            opts.iter()
+                .find(|o| o.get("name").and_then(Value::as_str) == Some(name))
+        })
+        .and_then(|o| o.get("value"))
+        .map(|v| v.as_bool().unwrap_or_else(|| v.as_str() == Some("true")))
+}
+
+/// Whether replies to this interaction are only visible to the requester:
+/// `public:true` opts out, otherwise `DISCORD_EPHEMERAL_DEFAULT` decides.
+fn interaction_ephemeral(data: &Value) -> bool {
+    match option_bool(data, "public") {
+        Some(public) => !public,
+        None => ephemeral_default(),
+    }
+}
+
+/// Turn one interaction into inbound messages sharing `metadata`.
+///
+/// Usually this is a single message. Message context-menu commands yield one
+/// message per referenced message, and `split_attachments:true` yields one
+/// message per attachment. Split items carry `discord.part: {index, count}`.
+fn build_interaction_messages(
+    data: &Value,
+    conversation_id: &str,
+    user_id: &str,
+    text: String,
+    metadata: Value,
+) -> Vec<InboundMessage> {
+    let make = |text: String, metadata: &Value| InboundMessage {
+        conversation_id: conversation_id.to_string(),
+        user_id: user_id.to_string(),
         text,
         metadata_json: metadata.to_string(),
-    }]
+    };
+
+    let referenced = data
+        .get("data")
+        .and_then(|d| d.get("resolved"))
+        .and_then(|r| r.get("messages"))
+        .and_then(Value::as_object)
+        .filter(|m| !m.is_empty());
+    if let Some(referenced) = referenced {
+        let count = referenced.len();
+        return referenced
+            .values()
+            .enumerate()
+            .map(|(index, message)| {
+                let mut meta = metadata.clone();
+                meta["discord"]["referenced_message"] = serde_json::json!({
+                    "id": message.get("id"),
+                    "channel_id": message.get("channel_id"),
+                    "author_id": message.get("author").and_then(|a| a.get("id"))
+                });
+                meta["discord"]["attachments"] =
+                    Value::Array(extract_attachments_from_message(message));
+                meta["discord"]["part"] = serde_json::json!({ "index": index, "count": count });
+                let content = message
+                    .get("content")
+                    .and_then(Value::as_str)
+                    .unwrap_or("")
+                    .trim();
+                let text = if content.is_empty() {
+                    text.clone()
+                } else {
+                    content.to_string()
+                };
+                make(text, &meta)
+            })
+            .collect();
+    }
+
+    let attachments = metadata["discord"]["attachments"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    if attachments.len() > 1 && option_flag(data, "split_attachments") {
+        let count = attachments.len();
+        return attachments
+            .into_iter()
+            .enumerate()
+            .map(|(index, attachment)| {
+                let mut meta = metadata.clone();
+                meta["discord"]["attachments"] = Value::Array(vec![attachment]);
+                meta["discord"]["part"] = serde_json::json!({ "index": index, "count": count });
+                make(text.clone(), &meta)
+            })
+            .collect();
+    }
+
+    vec![make(text, &metadata)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn signed_headers(key: &SigningKey, timestamp: &str, body: &str) -> Vec<(String, String)> {
+        let mut data = timestamp.as_bytes().to_vec();
+        data.extend_from_slice(body.as_bytes());
+        let signature = key.sign(&data);
+        vec![
+            (
+                "X-Signature-Ed25519".to_string(),
+                hex::encode(signature.to_bytes()),
+            ),
+            ("X-Signature-Timestamp".to_string(), timestamp.to_string()),
+        ]
+    }
+
+    fn verify(timestamp: &str) -> bool {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let public_key = hex::encode(key.verifying_key().to_bytes());
+        let body = r#"{"type":1}"#;
+        let headers = signed_headers(&key, timestamp, body);
+        verify_signature_at(&public_key, &headers, body, NOW)
+    }
+
+    #[test]
+    fn fresh_signature_is_accepted() {
+        assert!(verify(&NOW.to_string()));
+    }
+
+    #[test]
+    fn stale_signature_is_rejected() {
+        assert!(!verify(&(NOW - 600).to_string()));
+    }
+
+    #[test]
+    fn malformed_timestamp_is_rejected() {
+        assert!(!verify("not-a-timestamp"));
+    }
+
+    fn rate_limited(body: &str, headers: Vec<(String, String)>) -> HttpResponse {
+        HttpResponse {
+            status: 429,
+            headers,
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn rate_limit_wait_prefers_body_over_header() {
+        let headers = vec![("Retry-After".to_string(), "5".to_string())];
+        let body = r#"{"message":"You are being rate limited.","retry_after":0.25}"#;
+        let resp = rate_limited(body, headers.clone());
+        assert_eq!(rate_limit_retry_ms(&resp), Some(250));
+        assert_eq!(rate_limit_retry_ms(&rate_limited("", headers)), Some(5000));
+        assert_eq!(
+            rate_limit_retry_ms(&rate_limited("", vec![])),
+            Some(DEFAULT_RETRY_AFTER_MS)
+        );
+    }
+
+    #[test]
+    fn long_messages_split_on_line_and_word_boundaries() {
+        assert_eq!(split_message("short", 10), vec!["short"]);
+        assert_eq!(split_message("", 10), vec![""]);
+        assert_eq!(
+            split_message("one two\nthree four", 12),
+            vec!["one two", "three four"]
+        );
+        assert_eq!(
+            split_message("alpha beta gamma", 11),
+            vec!["alpha beta", "gamma"]
+        );
+        assert_eq!(split_message("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        let long = "é".repeat(2500);
+        let chunks = split_message(&long, MAX_MESSAGE_CHARS);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks
+            .iter()
+            .all(|c| c.chars().count() <= MAX_MESSAGE_CHARS));
+    }
+
+    #[test]
+    fn split_keeps_indentation_of_the_next_line() {
+        assert_eq!(
+            split_message("- item\n    - nested\n    - more", 20),
+            vec!["- item\n    - nested", "    - more"]
+        );
+        assert_eq!(split_message("a  b", 2), vec!["a", " b"]);
+    }
+
+    #[test]
+    fn split_code_block_is_closed_and_reopened() {
+        let text = "Here:\n```rust\nfn a() {\n    one();\n    two();\n}\n```\nDone.";
+        let chunks = split_message(text, 36);
+        assert_eq!(
+            chunks,
+            vec![
+                "Here:\n```rust\nfn a() {\n```",
+                "```rust\n    one();\n    two();\n}\n```",
+                "Done.",
+            ]
+        );
+        assert!(chunks.iter().all(|c| c.chars().count() <= 36));
+    }
+
+    #[test]
+    fn reply_to_guild_conversation_posts_to_its_channel() {
+        *CONFIG_CACHE.lock().unwrap() = Some(DiscordConfig {
+            application_id: "100000000000000000".to_string(),
+            public_key: String::new(),
+            bot_token: Some("bot-token".to_string()),
+            private_mode: PrivateMode::Open,
+            access: AccessControl::default(),
+            intents: None,
+        });
+        HTTP_POSTS.with(|posts| posts.borrow_mut().clear());
+        let request = serde_json::json!({
+            "conversation_id": "111111111111111111:222222222222222222",
+            "text": "hello"
+        });
+        let result: Value = serde_json::from_str(&send_message(&request.to_string())).unwrap();
+        assert_eq!(result["success"], true);
+        let urls: Vec<String> =
+            HTTP_POSTS.with(|posts| posts.borrow().iter().map(|(url, _)| url.clone()).collect());
+        assert_eq!(
+            urls,
+            vec!["https://discord.com/api/v10/channels/111111111111111111/messages"]
+        );
+        assert_eq!(
+            conversation_channel_id("user:222222222222222222"),
+            "user:222222222222222222"
+        );
+    }
+
+    fn created(id: &str) -> HttpResponse {
+        HttpResponse {
+            status: 200,
+            headers: vec![],
+            body: format!(r#"{{"id":"{}"}}"#, id),
+        }
+    }
+
+    #[test]
+    fn retry_after_partial_send_resumes_after_delivered_chunks() {
+        let chunks: Vec<String> = ["one", "two", "three"]
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        let mut posted = Vec::new();
+        let first = post_chunks(
+            &chunks,
+            None,
+            "create",
+            "create",
+            |_, chunk| chunk.to_string(),
+            |_, body| {
+                if body == "three" {
+                    return Some(HttpResponse {
+                        status: 500,
+                        headers: vec![],
+                        body: "oops".to_string(),
+                    });
+                }
+                posted.push(body.to_string());
+                Some(created(&format!("m{}", posted.len())))
+            },
+        )
+        .unwrap();
+        assert_eq!((first.sent, first.total), (2, 3));
+
+        let stored = sent_nonce_value(
+            first.last_sent_id.as_deref().unwrap(),
+            Some(first.sent),
+            NOW,
+        );
+        let resume = parse_sent_nonce(&stored, NOW + 5).unwrap();
+        assert_eq!(
+            resume,
+            SentNonce {
+                message_id: "m2".to_string(),
+                chunks_sent: Some(2)
+            }
+        );
+
+        let retry = post_chunks(
+            &chunks,
+            Some(&resume),
+            "create",
+            "create",
+            |_, chunk| chunk.to_string(),
+            |_, body| {
+                posted.push(body.to_string());
+                Some(created("m3"))
+            },
+        )
+        .unwrap();
+        assert_eq!((retry.sent, retry.total), (3, 3));
+        assert_eq!(posted, vec!["one", "two", "three"]);
+
+        assert_eq!(
+            parse_sent_nonce(&sent_nonce_value("m3", None, NOW), NOW + 5)
+                .unwrap()
+                .chunks_sent,
+            None
+        );
+        assert_eq!(
+            parse_sent_nonce(&stored, NOW + SEND_NONCE_TTL_SECS + 1),
+            None
+        );
+    }
+
+    #[test]
+    fn component_interactions_carry_custom_id_and_values() {
+        let button = serde_json::json!({
+            "type": 3,
+            "data": { "custom_id": "approve:42", "component_type": 2 }
+        });
+        let action = extract_component(&button);
+        assert_eq!(action.custom_id, "approve:42");
+        assert_eq!(action.component_type, 2);
+        assert_eq!(action.text(), "/tark component approve:42");
+
+        let select = serde_json::json!({
+            "type": 3,
+            "data": { "custom_id": "model", "component_type": 3, "values": ["a", "b"] }
+        });
+        assert_eq!(
+            extract_component(&select).text(),
+            "/tark component model a,b"
+        );
+    }
+
+    #[test]
+    fn access_control_admits_listed_users_and_roles() {
+        let open = AccessControl::default();
+        assert!(open.allows("1", &[]));
+
+        let cfg = serde_json::json!({
+            "allowed_roles": ["111111111111111111"],
+            "allowed_users": ["222222222222222222"]
+        });
+        let access = access_control_from_config(&cfg).unwrap();
+        assert!(access.allows("222222222222222222", &[]));
+        assert!(access.allows("3", &["111111111111111111".to_string()]));
+        assert!(!access.allows("3", &["444444444444444444".to_string()]));
+        assert!(
+            access_control_from_config(&serde_json::json!({ "allowed_roles": ["admins"] }))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn subcommands_keep_their_path_and_typed_values() {
+        let payload = serde_json::json!({
+            "data": {
+                "name": "tark",
+                "options": [{
+                    "type": 2,
+                    "name": "model",
+                    "options": [{
+                        "type": 1,
+                        "name": "set",
+                        "options": [
+                            { "type": 3, "name": "name", "value": "gpt-5" },
+                            { "type": 4, "name": "budget", "value": 3 },
+                            { "type": 5, "name": "default", "value": true }
+                        ]
+                    }]
+                }]
+            }
+        });
+        let (text, command) = extract_command(&payload);
+        assert_eq!(text, "/tark model set gpt-5 3 true");
+        assert_eq!(command["subcommand"], "model set");
+        assert_eq!(command["options"]["budget"], 3);
+
+        let flat = serde_json::json!({
+            "data": { "name": "tark", "options": [{ "type": 3, "name": "prompt", "value": "hi" }] }
+        });
+        assert_eq!(extract_command(&flat), ("hi".to_string(), Value::Null));
+    }
+
+    #[test]
+    fn intents_accept_numbers_and_names() {
+        let parse = |v: Value| intents_from_config(&serde_json::json!({ "intents": v }));
+        assert_eq!(intents_from_config(&serde_json::json!({})), Ok(None));
+        assert_eq!(parse(serde_json::json!(4097)), Ok(Some(4097)));
+        assert_eq!(
+            parse(serde_json::json!([
+                "guilds",
+                "DIRECT_MESSAGES",
+                "MESSAGE_CONTENT"
+            ])),
+            Ok(Some(DISCORD_INTENTS_DM_ONLY))
+        );
+        assert!(parse(serde_json::json!(["GUILD_MESSAGES"])).is_err());
+        assert!(parse(serde_json::json!(["NOT_AN_INTENT"])).is_err());
+        assert!(parse(serde_json::json!(1u64 << 40)).is_err());
+    }
+
+    #[test]
+    fn identify_is_limited_per_window() {
+        let mut sent_at = VecDeque::new();
+        for t in 0..IDENTIFY_LIMIT as u64 {
+            assert_eq!(identify_wait_secs(&mut sent_at, 100), None);
+            sent_at.push_back(100 + t / 2);
+        }
+        assert_eq!(identify_wait_secs(&mut sent_at, 102), Some(3));
+        assert_eq!(identify_wait_secs(&mut sent_at, 105), None);
+        assert_eq!(sent_at.len(), 3);
+    }
+
+    #[test]
+    fn modal_submit_collects_text_inputs() {
+        let payload = serde_json::json!({
+            "type": 5,
+            "data": {
+                "custom_id": "ask",
+                "components": [
+                    {
+                        "type": 1,
+                        "components": [{ "type": 4, "custom_id": "topic", "value": "rust" }]
+                    },
+                    {
+                        "type": 18,
+                        "component": { "type": 4, "custom_id": "detail", "value": "lifetimes" }
+                    }
+                ]
+            }
+        });
+        let modal = extract_modal(&payload);
+        assert_eq!(modal.custom_id, "ask");
+        assert_eq!(modal.text(), "topic: rust\ndetail: lifetimes");
+        assert_eq!(modal.to_json()["fields"]["detail"], "lifetimes");
+
+        let single = ModalSubmit {
+            custom_id: "ask".to_string(),
+            fields: vec![("prompt".to_string(), "hello".to_string())],
+        };
+        assert_eq!(single.text(), "hello");
+    }
 }
@@ -9,7 +9,7 @@ echo "Building gemini-auth WASM plugin..."
 rustup target add wasm32-wasip1 2>/dev/null || true
 
 # Build the plugin
-cargo build --target wasm32-wasip1 --release
+cargo build --target wasm32-wasip1 --release "$@"
 
 # Copy artifacts to dist folder
 mkdir -p dist
@@ -16,6 +16,7 @@
 //! - Project ID discovery via loadCodeAssist API
 
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 /// Safely truncate a string to at most `max_bytes` bytes without splitting UTF-8 characters.
 fn truncate_str(s: &str, max_bytes: usize) -> &str {
@@ -33,6 +34,7 @@ fn truncate_str(s: &str, max_bytes: usize) -> &str {
 // Host Function Imports (provided by tark)
 // =============================================================================
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:storage")]
 extern "C" {
     #[link_name = "get"]
@@ -40,8 +42,15 @@ extern "C" {
 
     #[link_name = "set"]
     fn storage_set_raw(key_ptr: i32, key_len: i32, val_ptr: i32, val_len: i32) -> i32;
+
+    #[link_name = "delete"]
+    fn storage_delete_raw(key_ptr: i32, key_len: i32) -> i32;
+
+    #[link_name = "keys"]
+    fn storage_keys_raw(prefix_ptr: i32, prefix_len: i32, ret_ptr: i32) -> i32;
 }
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:http")]
 extern "C" {
     #[link_name = "post"]
@@ -56,6 +65,7 @@ extern "C" {
     ) -> i32;
 }
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:log")]
 extern "C" {
     #[link_name = "info"]
@@ -66,18 +76,119 @@ extern "C" {
     fn log_debug_raw(msg_ptr: i32, msg_len: i32);
 }
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:env")]
 extern "C" {
     #[link_name = "get"]
     fn env_get_raw(name_ptr: i32, name_len: i32, ret_ptr: i32) -> i32;
 }
 
+#[cfg(not(test))]
 #[link(wasm_import_module = "tark:fs")]
 extern "C" {
     #[link_name = "read"]
     fn fs_read_raw(path_ptr: i32, path_len: i32, ret_ptr: i32) -> i32;
 }
 
+// =============================================================================
+// Host Function Stubs (native unit tests)
+// =============================================================================
+
+/// Stand-ins for the tark host imports so the plugin logic can be unit tested
+/// natively. Every call reports "unavailable" without touching `ret_ptr`;
+/// tests that need a host value set it in the thread-local mocks below.
+#[cfg(test)]
+mod host_stubs {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    thread_local! {
+        /// Variables `env_get` sees on this thread
+        pub static MOCK_ENV: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+        /// Host storage as seen from this thread
+        pub static MOCK_STORAGE: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+        /// Answers `http_post(url, body)` with the host's `{status, headers, body}` JSON
+        pub static MOCK_HTTP: RefCell<Option<MockHttp>> = RefCell::new(None);
+    }
+
+    pub type MockHttp = Box<dyn Fn(&str, &str) -> String>;
+
+    pub fn mock_env_get(name: &str) -> Option<String> {
+        MOCK_ENV.with(|env| env.borrow().get(name).cloned())
+    }
+
+    pub fn mock_storage_get(key: &str) -> Option<String> {
+        MOCK_STORAGE.with(|storage| storage.borrow().get(key).cloned())
+    }
+
+    pub fn mock_storage_set(key: &str, value: &str) -> bool {
+        MOCK_STORAGE.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert(key.to_string(), value.to_string())
+        });
+        true
+    }
+
+    pub fn mock_storage_delete(key: &str) -> bool {
+        MOCK_STORAGE.with(|storage| storage.borrow_mut().remove(key).is_some())
+    }
+
+    pub fn mock_http_post(url: &str, body: &str) -> Option<String> {
+        MOCK_HTTP.with(|http| http.borrow().as_ref().map(|respond| respond(url, body)))
+    }
+
+    pub unsafe fn storage_get_raw(_key_ptr: i32, _key_len: i32, _ret_ptr: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn storage_set_raw(
+        _key_ptr: i32,
+        _key_len: i32,
+        _val_ptr: i32,
+        _val_len: i32,
+    ) -> i32 {
+        -1
+    }
+
+    pub unsafe fn storage_delete_raw(_key_ptr: i32, _key_len: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn storage_keys_raw(_prefix_ptr: i32, _prefix_len: i32, _ret_ptr: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn http_post_raw(
+        _url_ptr: i32,
+        _url_len: i32,
+        _body_ptr: i32,
+        _body_len: i32,
+        _headers_ptr: i32,
+        _headers_len: i32,
+        _ret_ptr: i32,
+    ) -> i32 {
+        -1
+    }
+
+    pub unsafe fn log_info_raw(_msg_ptr: i32, _msg_len: i32) {}
+
+    pub unsafe fn log_error_raw(_msg_ptr: i32, _msg_len: i32) {}
+
+    pub unsafe fn log_debug_raw(_msg_ptr: i32, _msg_len: i32) {}
+
+    pub unsafe fn env_get_raw(_name_ptr: i32, _name_len: i32, _ret_ptr: i32) -> i32 {
+        -1
+    }
+
+    pub unsafe fn fs_read_raw(_path_ptr: i32, _path_len: i32, _ret_ptr: i32) -> i32 {
+        -1
+    }
+}
+
+#[cfg(test)]
+use host_stubs::*;
+
 // =============================================================================
 // Types
 // =============================================================================
@@ -100,8 +211,19 @@ struct PluginState {
     project_id: Option<String>,
 }
 
+/// Every storage key owned by this plugin (cleared by `plugin_reset`)
+const STORAGE_KEYS: &[&str] = &["state", START_CHECK_KEY];
+/// Storage key of the last `provider_start` connectivity check
+const START_CHECK_KEY: &str = "start_check";
+/// Environment variables checked, in order, for the Google Cloud project id
+const PROJECT_ENV_VARS: &[&str] = &["GOOGLE_CLOUD_PROJECT", "GCLOUD_PROJECT", "GCP_PROJECT"];
+/// Prefix of the backups written when stored state fails to parse
+const CORRUPT_STATE_PREFIX: &str = "state.corrupt.";
+
 const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
-const CODE_ASSIST_URL: &str = "https://cloudcode-pa.googleapis.com/v1internal";
+const CODE_ASSIST_HOST: &str = "https://cloudcode-pa.googleapis.com";
+/// Code Assist API version used unless `GEMINI_API_VERSION` overrides it
+const DEFAULT_API_VERSION: &str = "v1internal";
 
 #[derive(Debug, Clone)]
 struct OAuthClient {
@@ -109,11 +231,35 @@ struct OAuthClient {
     client_secret: String,
 }
 
+/// Where `load_oauth_client` may find OAuth client credentials.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientSource {
+    /// GEMINI_OAUTH_CLIENT_ID / GEMINI_OAUTH_CLIENT_SECRET
+    Env,
+    /// client_id / client_secret embedded in ~/.gemini/oauth_creds.json
+    File,
+    /// Extracted from the Gemini CLI installation
+    Cli,
+}
+
+const DEFAULT_CLIENT_SOURCE_ORDER: [ClientSource; 3] =
+    [ClientSource::Env, ClientSource::File, ClientSource::Cli];
+
+static LOGGED_CLIENT_SOURCE_ORDER: AtomicBool = AtomicBool::new(false);
+
 // =============================================================================
 // Memory Management
 // =============================================================================
 
-static mut RETURN_BUFFER: [u8; 131072] = [0u8; 131072]; // 128KB for responses
+/// Capacity of RETURN_BUFFER, chosen with the `buffer-*` Cargo features
+#[cfg(feature = "buffer-512k")]
+const RETURN_BUFFER_SIZE: usize = 512 * 1024;
+#[cfg(all(feature = "buffer-256k", not(feature = "buffer-512k")))]
+const RETURN_BUFFER_SIZE: usize = 256 * 1024;
+#[cfg(not(any(feature = "buffer-256k", feature = "buffer-512k")))]
+const RETURN_BUFFER_SIZE: usize = 128 * 1024;
+
+static mut RETURN_BUFFER: [u8; RETURN_BUFFER_SIZE] = [0u8; RETURN_BUFFER_SIZE];
 static mut ENV_BUFFER: [u8; 256] = [0u8; 256];
 
 fn return_buffer_ptr() -> i32 {
@@ -127,7 +273,8 @@ fn env_buffer_ptr() -> i32 {
 }
 
 unsafe fn return_buffer_bytes(len: i32) -> &'static [u8] {
-    std::slice::from_raw_parts(std::ptr::addr_of!(RETURN_BUFFER).cast::<u8>(), len as usize)
+    let len = (len as usize).min(RETURN_BUFFER_SIZE);
+    std::slice::from_raw_parts(std::ptr::addr_of!(RETURN_BUFFER).cast::<u8>(), len)
 }
 
 unsafe fn env_buffer_bytes(len: i32) -> &'static [u8] {
@@ -169,6 +316,10 @@ fn log_debug(msg: &str) {
 }
 
 fn storage_get(key: &str) -> Option<String> {
+    #[cfg(test)]
+    if let Some(value) = mock_storage_get(key) {
+        return Some(value);
+    }
     unsafe {
         let ret = storage_get_raw(key.as_ptr() as i32, key.len() as i32, return_buffer_ptr());
         if ret > 0 {
@@ -180,6 +331,10 @@ fn storage_get(key: &str) -> Option<String> {
 }
 
 fn storage_set(key: &str, value: &str) -> bool {
+    #[cfg(test)]
+    if mock_storage_set(key, value) {
+        return true;
+    }
     unsafe {
         storage_set_raw(
             key.as_ptr() as i32,
@@ -190,7 +345,35 @@ fn storage_set(key: &str, value: &str) -> bool {
     }
 }
 
+fn storage_delete(key: &str) -> bool {
+    #[cfg(test)]
+    if mock_storage_delete(key) {
+        return true;
+    }
+    unsafe { storage_delete_raw(key.as_ptr() as i32, key.len() as i32) == 0 }
+}
+
+/// Stored keys starting with `prefix` (the host returns a JSON array)
+fn storage_keys(prefix: &str) -> Vec<String> {
+    unsafe {
+        let ret = storage_keys_raw(
+            prefix.as_ptr() as i32,
+            prefix.len() as i32,
+            return_buffer_ptr(),
+        );
+        if ret > 0 {
+            serde_json::from_slice(return_buffer_bytes(ret)).unwrap_or_default()
+        } else {
+            Vec::new()
+        }
+    }
+}
+
 fn http_post(url: &str, body: &str, headers: &[(String, String)]) -> Option<String> {
+    #[cfg(test)]
+    if let Some(raw) = mock_http_post(url, body) {
+        return Some(raw);
+    }
     let headers_json = serde_json::to_string(headers).unwrap_or_default();
     unsafe {
         let ret = http_post_raw(
@@ -210,7 +393,233 @@ fn http_post(url: &str, body: &str, headers: &[(String, String)]) -> Option<Stri
     }
 }
 
+/// How a host HTTP call turned out, so retry/refresh/error decisions are
+/// made the same way everywhere
+#[derive(Debug, PartialEq)]
+enum HttpOutcome {
+    /// 2xx with the response body
+    Success(String),
+    /// 408 or 5xx, worth retrying later
+    Retryable(u16),
+    /// 401, the credentials were rejected
+    Unauthorized,
+    /// 429, with the Retry-After delay in seconds when the server sent one
+    RateLimited { retry_after: Option<u64> },
+    /// Any other non-2xx status
+    ClientError { status: u16, body: String },
+    /// The host could not complete the request
+    TransportError,
+}
+
+impl std::fmt::Display for HttpOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpOutcome::Success(_) => write!(f, "success"),
+            HttpOutcome::Retryable(status) => write!(f, "HTTP {} (retryable)", status),
+            HttpOutcome::Unauthorized => write!(f, "HTTP 401 (unauthorized)"),
+            HttpOutcome::RateLimited {
+                retry_after: Some(secs),
+            } => write!(f, "HTTP 429 (retry after {}s)", secs),
+            HttpOutcome::RateLimited { retry_after: None } => write!(f, "HTTP 429"),
+            HttpOutcome::ClientError { status, .. } => write!(f, "HTTP {}", status),
+            HttpOutcome::TransportError => write!(f, "transport error"),
+        }
+    }
+}
+
+/// Classify the host's `{status, headers, body}` wrapper. A response that is
+/// not wrapped is treated as a successful raw body.
+fn classify_response(raw: &str) -> HttpOutcome {
+    let parsed: serde_json::Value = match serde_json::from_str(raw) {
+        Ok(v) => v,
+        Err(_) => return HttpOutcome::Success(raw.to_string()),
+    };
+    let body = match parsed.get("body").and_then(|b| b.as_str()) {
+        Some(body) => body.to_string(),
+        None => return HttpOutcome::Success(raw.to_string()),
+    };
+    let status = parsed.get("status").and_then(|s| s.as_u64()).unwrap_or(0) as u16;
+    match status {
+        200..=299 => HttpOutcome::Success(body),
+        401 => HttpOutcome::Unauthorized,
+        429 => HttpOutcome::RateLimited {
+            retry_after: response_header(&parsed, "retry-after")
+                .and_then(|v| v.trim().parse().ok()),
+        },
+        408 | 500..=599 => HttpOutcome::Retryable(status),
+        0 => HttpOutcome::TransportError,
+        _ => HttpOutcome::ClientError { status, body },
+    }
+}
+
+/// Header from the host wrapper, which sends either `[[name, value]]` pairs
+/// or a `{name: value}` object
+fn response_header(parsed: &serde_json::Value, name: &str) -> Option<String> {
+    match parsed.get("headers")? {
+        serde_json::Value::Array(pairs) => pairs.iter().find_map(|pair| {
+            let key = pair.get(0)?.as_str()?;
+            if key.eq_ignore_ascii_case(name) {
+                pair.get(1)?.as_str().map(String::from)
+            } else {
+                None
+            }
+        }),
+        serde_json::Value::Object(map) => map
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, value)| value.as_str().map(String::from)),
+        _ => None,
+    }
+}
+
+/// POST through the host and classify the result
+fn http_post_outcome(url: &str, body: &str, headers: &[(String, String)]) -> HttpOutcome {
+    match http_post(url, body, headers) {
+        Some(raw) => classify_response(&raw),
+        None => HttpOutcome::TransportError,
+    }
+}
+
+/// Why a token refresh failed
+#[derive(Debug)]
+enum RefreshError {
+    /// The refresh token was rejected; only re-authenticating helps
+    InvalidGrant(String),
+    /// The token endpoint answered with a JSON OAuth error other than invalid_grant
+    OAuth {
+        error: String,
+        description: Option<String>,
+    },
+    /// The token endpoint answered with something that is not JSON (an
+    /// HTML gateway page, plain text, ...)
+    NonJson {
+        status: u16,
+        content_type: Option<String>,
+        body: String,
+    },
+    /// The request failed without a usable body
+    Http(HttpOutcome),
+}
+
+impl std::fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefreshError::InvalidGrant(description) => {
+                write!(f, "refresh token rejected (invalid_grant: {})", description)
+            }
+            RefreshError::OAuth { error, description } => match description {
+                Some(description) => write!(f, "auth server error {}: {}", error, description),
+                None => write!(f, "auth server error {}", error),
+            },
+            RefreshError::NonJson {
+                status,
+                content_type,
+                body,
+            } => write!(
+                f,
+                "auth server returned non-JSON (HTTP {}, content-type {}): {}",
+                status,
+                content_type.as_deref().unwrap_or("unknown"),
+                body
+            ),
+            RefreshError::Http(outcome) => write!(f, "{}", outcome),
+        }
+    }
+}
+
+/// Default and upper bound for `PLUGIN_LOG_BODY_BYTES`
+const DEFAULT_LOG_BODY_BYTES: usize = 500;
+const MAX_LOG_BODY_BYTES: usize = 16 * 1024;
+
+/// How much of an unparseable response body to keep in logs and errors
+fn log_body_bytes() -> usize {
+    env_get("PLUGIN_LOG_BODY_BYTES")
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(DEFAULT_LOG_BODY_BYTES)
+        .min(MAX_LOG_BODY_BYTES)
+}
+
+/// Leading part of `body`, at most `max_bytes` long and cut on a char boundary
+fn body_excerpt(body: &str, max_bytes: usize) -> String {
+    let mut end = body.len().min(max_bytes);
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    body[..end].to_string()
+}
+
+/// Parse a token endpoint response into `T`, telling OAuth errors apart
+/// from bodies that are not JSON at all
+fn parse_token_response<T: serde::de::DeserializeOwned>(raw: &str) -> Result<T, RefreshError> {
+    let outcome = classify_response(raw);
+    let wrapper: Option<serde_json::Value> = serde_json::from_str(raw)
+        .ok()
+        .filter(|v: &serde_json::Value| v.get("body").and_then(|b| b.as_str()).is_some());
+    let (status, content_type, body) = match &wrapper {
+        Some(w) => (
+            w.get("status").and_then(|s| s.as_u64()).unwrap_or(0) as u16,
+            response_header(w, "content-type"),
+            w.get("body")
+                .and_then(|b| b.as_str())
+                .unwrap_or("")
+                .to_string(),
+        ),
+        None => (200, None, raw.to_string()),
+    };
+
+    if let HttpOutcome::Success(_) = outcome {
+        if let Ok(token) = serde_json::from_str::<T>(&body) {
+            return Ok(token);
+        }
+    }
+
+    match serde_json::from_str::<serde_json::Value>(&body) {
+        Ok(json) => {
+            // OAuth errors are `{error, error_description}`; Google APIs also
+            // answer `{error: {status, message}}`, whose message is kept whole.
+            let error_object = json.get("error").filter(|e| e.is_object());
+            let error = json.get("error").and_then(|e| e.as_str()).or_else(|| {
+                error_object
+                    .and_then(|e| e.get("status"))
+                    .and_then(|s| s.as_str())
+                    .or(error_object.map(|_| "error"))
+            });
+            let description = json
+                .get("error_description")
+                .or_else(|| error_object.and_then(|e| e.get("message")))
+                .and_then(|d| d.as_str())
+                .map(String::from);
+            match error {
+                Some("invalid_grant") => Err(RefreshError::InvalidGrant(
+                    description.unwrap_or_else(|| "no description".to_string()),
+                )),
+                Some(error) => Err(RefreshError::OAuth {
+                    error: error.to_string(),
+                    description,
+                }),
+                None if matches!(outcome, HttpOutcome::Success(_)) => Err(RefreshError::OAuth {
+                    error: "invalid_response".to_string(),
+                    description: Some("no access_token in response".to_string()),
+                }),
+                None => Err(RefreshError::Http(outcome)),
+            }
+        }
+        Err(_) if body.trim().is_empty() && !matches!(outcome, HttpOutcome::Success(_)) => {
+            Err(RefreshError::Http(outcome))
+        }
+        Err(_) => Err(RefreshError::NonJson {
+            status,
+            content_type,
+            body: body_excerpt(&body, log_body_bytes()),
+        }),
+    }
+}
+
 fn env_get(name: &str) -> Option<String> {
+    #[cfg(test)]
+    if let Some(value) = mock_env_get(name) {
+        return Some(value);
+    }
     unsafe {
         let len = env_get_raw(name.as_ptr() as i32, name.len() as i32, env_buffer_ptr());
         if len <= 0 {
@@ -226,6 +635,10 @@ fn env_get(name: &str) -> Option<String> {
 
 /// Read a file from the filesystem (if allowed by capabilities)
 fn fs_read(path: &str) -> Option<String> {
+    if !path_in_allowlist(path, &fs_allowlist()) {
+        log_fs_refusal(path);
+        return None;
+    }
     unsafe {
         let ret = fs_read_raw(path.as_ptr() as i32, path.len() as i32, return_buffer_ptr());
         if ret > 0 {
@@ -238,6 +651,64 @@ fn fs_read(path: &str) -> Option<String> {
     }
 }
 
+/// Paths the plugin may read. `PLUGIN_FS_ALLOWLIST` replaces the default
+/// (the Gemini CLI oauth2.js locations and the endpoints file) with a
+/// colon-separated list; an entry ending in `/` allows everything below that
+/// directory.
+fn fs_allowlist() -> Vec<String> {
+    match env_get("PLUGIN_FS_ALLOWLIST") {
+        Some(list) => list
+            .split(':')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => GEMINI_CLI_OAUTH2_PATHS
+            .iter()
+            .map(|path| path.to_string())
+            .chain(std::iter::once(endpoints_path()))
+            .collect(),
+    }
+}
+
+fn path_in_allowlist(path: &str, allowlist: &[String]) -> bool {
+    if path.split('/').any(|part| part == "..") {
+        return false;
+    }
+    allowlist.iter().any(|entry| {
+        if entry.ends_with('/') {
+            path.starts_with(entry.as_str())
+        } else {
+            path == entry
+        }
+    })
+}
+
+/// Paths whose allowlist refusal has been logged already
+static LOGGED_FS_REFUSALS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+/// Log that `path` is outside the fs allowlist, once per path: the endpoints
+/// file is looked up on every token request
+fn log_fs_refusal(path: &str) {
+    let first = LOGGED_FS_REFUSALS
+        .lock()
+        .map(|mut logged| {
+            if logged.iter().any(|p| p == path) {
+                false
+            } else {
+                logged.push(path.to_string());
+                true
+            }
+        })
+        .unwrap_or(true);
+    if first {
+        log_error(&format!(
+            "Refusing to read '{}': not in the plugin fs allowlist (set PLUGIN_FS_ALLOWLIST to change it)",
+            path
+        ));
+    }
+}
+
 // =============================================================================
 // Gemini CLI Credential Extraction
 // =============================================================================
@@ -301,10 +772,7 @@ fn extract_js_const(content: &str, name: &str) -> Option<String> {
 
 fn load_state() -> PluginState {
     match storage_get("state") {
-        Some(s) => serde_json::from_str(&s).unwrap_or(PluginState {
-            credentials: None,
-            project_id: None,
-        }),
+        Some(s) => serde_json::from_str(&s).unwrap_or_else(|e| recover_corrupt_state(&s, &e)),
         None => PluginState {
             credentials: None,
             project_id: None,
@@ -312,6 +780,57 @@ fn load_state() -> PluginState {
     }
 }
 
+/// Handle stored state that no longer parses: keep the raw JSON under
+/// `state.corrupt.<ts>`, salvage what we can, and persist the result so the
+/// backup only happens once.
+fn recover_corrupt_state(raw: &str, err: &serde_json::Error) -> PluginState {
+    let backup_key = format!("{}{}", CORRUPT_STATE_PREFIX, now_ms());
+    storage_set(&backup_key, raw);
+    log_error(&format!(
+        "Stored state is corrupt ({}); backed up to '{}'",
+        err, backup_key
+    ));
+
+    let state = salvage_state(raw).unwrap_or_else(|| {
+        log_error("Nothing salvageable in corrupt state, starting fresh");
+        PluginState {
+            credentials: None,
+            project_id: None,
+        }
+    });
+    save_state(&state);
+    state
+}
+
+/// Leniently pull tokens and project id out of a state blob that failed strict parsing
+fn salvage_state(raw: &str) -> Option<PluginState> {
+    let value: serde_json::Value = serde_json::from_str(raw).ok()?;
+    let creds = value
+        .get("credentials")
+        .filter(|c| c.is_object())
+        .unwrap_or(&value);
+    let text =
+        |v: &serde_json::Value, key: &str| v.get(key).and_then(|x| x.as_str()).map(String::from);
+
+    let credentials = text(creds, "access_token").map(|access_token| OAuthCredentials {
+        access_token,
+        refresh_token: text(creds, "refresh_token"),
+        expiry_date: creds.get("expiry_date").and_then(|x| x.as_u64()),
+        token_type: text(creds, "token_type"),
+        client_id: text(creds, "client_id"),
+        client_secret: text(creds, "client_secret"),
+    });
+    let project_id = text(&value, "project_id");
+    if credentials.is_none() && project_id.is_none() {
+        return None;
+    }
+    log_info("Recovered credentials/project from corrupt state");
+    Some(PluginState {
+        credentials,
+        project_id,
+    })
+}
+
 fn save_state(state: &PluginState) {
     if let Ok(json) = serde_json::to_string(state) {
         storage_set("state", &json);
@@ -334,40 +853,52 @@ fn is_expired(creds: &OAuthCredentials) -> bool {
     creds.expiry_date.map(|exp| now >= exp).unwrap_or(false)
 }
 
-fn load_oauth_client(creds: &OAuthCredentials) -> Option<OAuthClient> {
-    // 1. Try environment variables
-    let env_id = env_get("GEMINI_OAUTH_CLIENT_ID");
-    let env_secret = env_get("GEMINI_OAUTH_CLIENT_SECRET");
-    if let (Some(client_id), Some(client_secret)) = (env_id, env_secret) {
-        if !client_id.is_empty() && !client_secret.is_empty() {
-            log_debug("Using OAuth client from environment variables");
-            return Some(OAuthClient {
-                client_id,
-                client_secret,
-            });
+/// Resolve the OAuth client source precedence from `GEMINI_AUTH_ORDER`
+/// (comma-separated `env`, `file`, `cli`). Sources left out are not tried.
+fn client_source_order() -> Vec<ClientSource> {
+    let mut order = Vec::new();
+    if let Some(raw) = env_get("GEMINI_AUTH_ORDER") {
+        for name in raw.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let source = match name.to_ascii_lowercase().as_str() {
+                "env" => ClientSource::Env,
+                "file" => ClientSource::File,
+                "cli" => ClientSource::Cli,
+                _ => {
+                    log_error(&format!(
+                        "GEMINI_AUTH_ORDER: ignoring unknown source '{}'",
+                        name
+                    ));
+                    continue;
+                }
+            };
+            if !order.contains(&source) {
+                order.push(source);
+            }
+        }
+        if order.is_empty() {
+            log_error("GEMINI_AUTH_ORDER has no valid sources, using default order");
         }
     }
+    if order.is_empty() {
+        order = DEFAULT_CLIENT_SOURCE_ORDER.to_vec();
+    }
 
-    // 2. Try credentials file (from ~/.gemini/oauth_creds.json)
-    if let (Some(client_id), Some(client_secret)) =
-        (creds.client_id.clone(), creds.client_secret.clone())
-    {
-        if !client_id.is_empty() && !client_secret.is_empty() {
-            log_debug("Using OAuth client from credentials file");
-            return Some(OAuthClient {
-                client_id,
-                client_secret,
-            });
-        }
+    if !LOGGED_CLIENT_SOURCE_ORDER.swap(true, Ordering::SeqCst) {
+        log_info(&format!("OAuth client source order: {:?}", order));
     }
+    order
+}
 
-    // 3. Try extracting from Gemini CLI installation (dynamic discovery)
-    if let Some((client_id, client_secret)) = extract_gemini_cli_credentials() {
-        log_debug("Using OAuth client from Gemini CLI installation");
-        return Some(OAuthClient {
-            client_id,
-            client_secret,
-        });
+fn load_oauth_client(creds: &OAuthCredentials) -> Option<OAuthClient> {
+    for source in client_source_order() {
+        let client = match source {
+            ClientSource::Env => load_oauth_client_from_env(),
+            ClientSource::File => load_oauth_client_from_creds(creds),
+            ClientSource::Cli => load_oauth_client_from_cli(),
+        };
+        if client.is_some() {
+            return client;
+        }
     }
 
     // No client credentials available.
@@ -378,45 +909,239 @@ fn load_oauth_client(creds: &OAuthCredentials) -> Option<OAuthClient> {
     None
 }
 
-fn refresh_token(refresh_token: &str, oauth_client: &OAuthClient) -> Option<OAuthCredentials> {
+fn load_oauth_client_from_env() -> Option<OAuthClient> {
+    let client_id = env_get("GEMINI_OAUTH_CLIENT_ID")?;
+    let client_secret = env_get("GEMINI_OAUTH_CLIENT_SECRET")?;
+    if client_id.is_empty() || client_secret.is_empty() {
+        return None;
+    }
+    log_debug("Using OAuth client from environment variables");
+    Some(OAuthClient {
+        client_id,
+        client_secret,
+    })
+}
+
+fn load_oauth_client_from_creds(creds: &OAuthCredentials) -> Option<OAuthClient> {
+    let client_id = creds.client_id.clone()?;
+    let client_secret = creds.client_secret.clone()?;
+    if client_id.is_empty() || client_secret.is_empty() {
+        return None;
+    }
+    log_debug("Using OAuth client from credentials file");
+    Some(OAuthClient {
+        client_id,
+        client_secret,
+    })
+}
+
+/// `GEMINI_DISABLE_CLI_EXTRACTION=1` forbids scanning the Gemini CLI bundle
+fn cli_extraction_disabled() -> bool {
+    matches!(
+        env_get("GEMINI_DISABLE_CLI_EXTRACTION").as_deref(),
+        Some("1") | Some("true") | Some("yes")
+    )
+}
+
+fn load_oauth_client_from_cli() -> Option<OAuthClient> {
+    if cli_extraction_disabled() {
+        log_info(
+            "Gemini CLI credential extraction disabled by GEMINI_DISABLE_CLI_EXTRACTION; \
+             use GEMINI_OAUTH_CLIENT_ID/GEMINI_OAUTH_CLIENT_SECRET or credentials file client info",
+        );
+        return None;
+    }
+    let (client_id, client_secret) = extract_gemini_cli_credentials()?;
+    log_debug("Using OAuth client from Gemini CLI installation");
+    Some(OAuthClient {
+        client_id,
+        client_secret,
+    })
+}
+
+/// Endpoint overrides from `~/.config/tark/gemini_endpoints.json`
+#[derive(Debug, Default, Deserialize)]
+struct EndpointConfig {
+    #[serde(default)]
+    token_url: Option<String>,
+    /// Code Assist host, without the API version
+    #[serde(default)]
+    api_endpoint: Option<String>,
+    /// Full `loadCodeAssist` URL used for project discovery
+    #[serde(default)]
+    discovery_url: Option<String>,
+}
+
+fn endpoints_path() -> String {
+    match env_get("HOME") {
+        Some(home) => format!("{}/.config/tark/gemini_endpoints.json", home),
+        None => "~/.config/tark/gemini_endpoints.json".to_string(),
+    }
+}
+
+/// Load the endpoints file. A missing file means no overrides; an invalid
+/// one is logged and ignored.
+fn endpoint_config() -> EndpointConfig {
+    let path = endpoints_path();
+    let Some(content) = fs_read(&path) else {
+        return EndpointConfig::default();
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        log_error(&format!("Ignoring invalid endpoints file {}: {}", path, e));
+        EndpointConfig::default()
+    })
+}
+
+/// Env var first, then the endpoints file, then the built-in default
+fn pick_endpoint(env: Option<String>, file: Option<String>, default: &str) -> String {
+    env.into_iter()
+        .chain(file)
+        .map(|url| url.trim().to_string())
+        .find(|url| !url.is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
+
+/// Token endpoint, overridable via `GEMINI_TOKEN_URL` (e.g. a local mock in CI)
+/// or `token_url` in the endpoints file
+fn token_url() -> String {
+    pick_endpoint(
+        env_get("GEMINI_TOKEN_URL"),
+        endpoint_config().token_url,
+        TOKEN_URL,
+    )
+}
+
+/// Code Assist host, overridable via `GEMINI_CODE_ASSIST_ENDPOINT` or
+/// `api_endpoint` in the endpoints file
+fn code_assist_host() -> String {
+    pick_endpoint(
+        env_get("GEMINI_CODE_ASSIST_ENDPOINT"),
+        endpoint_config().api_endpoint,
+        CODE_ASSIST_HOST,
+    )
+}
+
+/// Parameters the refresh body already carries; extras may not override them
+const RESERVED_REFRESH_PARAMS: &[&str] =
+    &["client_id", "client_secret", "refresh_token", "grant_type"];
+
+/// Extra `key=value` pairs for the refresh request from
+/// `GEMINI_REFRESH_EXTRA_PARAMS` (`&`-separated, values may be URL-encoded),
+/// e.g. `audience=...&resource=...`. Invalid or reserved pairs are logged
+/// and skipped.
+fn refresh_extra_params() -> Vec<(String, String)> {
+    match env_get("GEMINI_REFRESH_EXTRA_PARAMS") {
+        Some(raw) => parse_extra_params(&raw),
+        None => Vec::new(),
+    }
+}
+
+fn parse_extra_params(raw: &str) -> Vec<(String, String)> {
+    raw.split('&')
+        .filter(|pair| !pair.trim().is_empty())
+        .filter_map(|pair| {
+            let (key, value) = match pair.split_once('=') {
+                Some((key, value)) => (urlencoding_decode(key.trim()), urlencoding_decode(value)),
+                None => (None, None),
+            };
+            match (key, value) {
+                (Some(key), Some(value))
+                    if !key.is_empty() && !RESERVED_REFRESH_PARAMS.contains(&key.as_str()) =>
+                {
+                    Some((key, value))
+                }
+                _ => {
+                    log_error(&format!(
+                        "Ignoring invalid or reserved GEMINI_REFRESH_EXTRA_PARAMS entry '{}'",
+                        pair
+                    ));
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Simple URL encoding for form data
+fn urlencoding_encode(input: &str) -> String {
+    let mut result = String::new();
+    for c in input.chars() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' | '~' => {
+                result.push(c);
+            }
+            _ => {
+                for byte in c.to_string().as_bytes() {
+                    result.push_str(&format!("%{:02X}", byte));
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Decode `%XX` escapes and `+`; None for malformed escapes or non-UTF-8
+fn urlencoding_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = input.get(i + 1..i + 3)?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn refresh_token(
+    refresh_token: &str,
+    oauth_client: &OAuthClient,
+) -> Result<OAuthCredentials, RefreshError> {
     log_debug("Refreshing OAuth token...");
 
-    let body = format!(
+    let mut body = format!(
         "client_id={}&client_secret={}&refresh_token={}&grant_type=refresh_token",
-        oauth_client.client_id, oauth_client.client_secret, refresh_token
+        urlencoding_encode(&oauth_client.client_id),
+        urlencoding_encode(&oauth_client.client_secret),
+        urlencoding_encode(refresh_token)
     );
+    for (key, value) in refresh_extra_params() {
+        body.push_str(&format!(
+            "&{}={}",
+            urlencoding_encode(&key),
+            urlencoding_encode(&value)
+        ));
+    }
 
     let headers = vec![(
         "Content-Type".to_string(),
         "application/x-www-form-urlencoded".to_string(),
     )];
 
-    let response = http_post(TOKEN_URL, &body, &headers)?;
-
     #[derive(Deserialize)]
     struct TokenResponse {
         access_token: String,
         expires_in: Option<u64>,
     }
 
-    let parsed: serde_json::Value = serde_json::from_str(&response).ok()?;
-
-    // Handle HTTP wrapper from host
-    let body_str = if let Some(body) = parsed.get("body").and_then(|b| b.as_str()) {
-        let status = parsed.get("status").and_then(|s| s.as_u64()).unwrap_or(0);
-        if status != 200 {
-            log_error(&format!("Token refresh failed: HTTP {}", status));
-            return None;
-        }
-        body.to_string()
-    } else {
-        response
-    };
-
-    let token_data: TokenResponse = serde_json::from_str(&body_str).ok()?;
+    let response = http_post(&token_url(), &body, &headers)
+        .ok_or(RefreshError::Http(HttpOutcome::TransportError))?;
+    let token_data: TokenResponse = parse_token_response(&response)?;
 
     log_info("Token refreshed successfully");
-    Some(OAuthCredentials {
+    Ok(OAuthCredentials {
         access_token: token_data.access_token,
         refresh_token: Some(refresh_token.to_string()),
         expiry_date: token_data.expires_in.map(|s| now_ms() + s * 1000),
@@ -441,13 +1166,23 @@ fn get_valid_token() -> Result<String, String> {
     // Token expired - try to refresh
     if let Some(refresh) = &creds.refresh_token {
         match load_oauth_client(creds) {
-            Some(oauth_client) => {
-                if let Some(new_creds) = refresh_token(refresh, &oauth_client) {
+            Some(oauth_client) => match refresh_token(refresh, &oauth_client) {
+                Ok(new_creds) => {
                     state.credentials = Some(new_creds.clone());
                     save_state(&state);
                     return Ok(new_creds.access_token);
                 }
-            }
+                Err(RefreshError::InvalidGrant(description)) => {
+                    log_error(&format!(
+                        "Token refresh failed: invalid_grant ({})",
+                        description
+                    ));
+                    return Err("Refresh token was rejected (invalid_grant). \
+                             Run 'gemini auth login' to sign in again."
+                        .to_string());
+                }
+                Err(e) => log_error(&format!("Token refresh failed: {}", e)),
+            },
             None => {
                 // Cannot refresh without client credentials - fail with clear message
                 return Err(
@@ -485,13 +1220,47 @@ fn get_project_id() -> Option<String> {
     }
 
     // Try environment variables
-    env_get("GOOGLE_CLOUD_PROJECT")
-        .or_else(|| env_get("GCLOUD_PROJECT"))
-        .or_else(|| env_get("GCP_PROJECT"))
+    PROJECT_ENV_VARS.iter().find_map(|name| env_get(name))
+}
+
+/// Code Assist API version, overridable via `GEMINI_API_VERSION` (e.g. to opt
+/// into a newer endpoint before this plugin defaults to it)
+fn api_version() -> String {
+    match env_get("GEMINI_API_VERSION") {
+        Some(version) if is_valid_api_version(&version) => version,
+        Some(version) => {
+            log_error(&format!(
+                "Ignoring invalid GEMINI_API_VERSION '{}', using {}",
+                version, DEFAULT_API_VERSION
+            ));
+            DEFAULT_API_VERSION.to_string()
+        }
+        None => DEFAULT_API_VERSION.to_string(),
+    }
+}
+
+/// A version is a single path segment like `v1internal` or `v1beta`
+fn is_valid_api_version(version: &str) -> bool {
+    version.starts_with('v')
+        && version.len() <= 32
+        && version.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn code_assist_url() -> String {
+    format!("{}/{}", code_assist_host(), api_version())
+}
+
+/// `loadCodeAssist` URL, overridable via `discovery_url` in the endpoints file
+fn discovery_url() -> String {
+    pick_endpoint(
+        None,
+        endpoint_config().discovery_url,
+        &format!("{}:loadCodeAssist", code_assist_url()),
+    )
 }
 
 fn discover_project_id(access_token: &str) -> Option<String> {
-    let url = format!("{}:loadCodeAssist", CODE_ASSIST_URL);
+    let url = discovery_url();
 
     let request_body = serde_json::json!({
         "metadata": {
@@ -519,17 +1288,14 @@ fn discover_project_id(access_token: &str) -> Option<String> {
 
     log_debug("Discovering project via loadCodeAssist...");
 
-    let response = http_post(&url, &request_body.to_string(), &headers)?;
-    let parsed: serde_json::Value = serde_json::from_str(&response).ok()?;
-
-    let status = parsed.get("status").and_then(|s| s.as_u64()).unwrap_or(0);
-    if status != 200 {
-        log_error(&format!("loadCodeAssist failed: HTTP {}", status));
-        return None;
-    }
-
-    let body_str = parsed.get("body").and_then(|b| b.as_str())?;
-    let body: serde_json::Value = serde_json::from_str(body_str).ok()?;
+    let body_str = match http_post_outcome(&url, &request_body.to_string(), &headers) {
+        HttpOutcome::Success(body) => body,
+        outcome => {
+            log_error(&format!("loadCodeAssist failed: {}", outcome));
+            return None;
+        }
+    };
+    let body: serde_json::Value = serde_json::from_str(&body_str).ok()?;
 
     let project_id = body
         .get("cloudaicompanionProject")
@@ -551,15 +1317,68 @@ fn discover_project_id(access_token: &str) -> Option<String> {
 // Provider Plugin Interface - Auth Only
 // =============================================================================
 
+/// Called by tark when the plugin is loaded. Logs the plugin version and
+/// checks that the token endpoint answers; any HTTP status counts as
+/// reachable. The result is stored under `start_check` and shown by
+/// `provider_info`. Never fails, since the network may be down at start.
+/// Set `PLUGIN_START_CHECK=0` to skip the request.
+#[no_mangle]
+pub extern "C" fn provider_start() -> i32 {
+    log_info(&format!(
+        "gemini-oauth {} started",
+        env!("CARGO_PKG_VERSION")
+    ));
+    if env_get("PLUGIN_START_CHECK").as_deref() == Some("0") {
+        return 0;
+    }
+
+    let url = token_url();
+    let headers = vec![(
+        "Content-Type".to_string(),
+        "application/x-www-form-urlencoded".to_string(),
+    )];
+    let outcome = http_post_outcome(&url, "", &headers);
+    let reachable = !matches!(outcome, HttpOutcome::TransportError);
+    if reachable {
+        log_info(&format!(
+            "Token endpoint {} is reachable ({})",
+            url, outcome
+        ));
+    } else {
+        log_error(&format!("Token endpoint {} is not reachable", url));
+    }
+    let check = serde_json::json!({
+        "checked_at": now_ms() / 1000,
+        "url": url,
+        "reachable": reachable,
+        "outcome": outcome.to_string()
+    });
+    storage_set(START_CHECK_KEY, &check.to_string());
+    0
+}
+
 /// Get provider info (JSON)
 #[no_mangle]
 pub extern "C" fn provider_info(ret_ptr: i32) -> i32 {
+    let state = load_state();
+    let project_id_source = if state.project_id.is_some() {
+        Some("state")
+    } else if PROJECT_ENV_VARS.iter().any(|name| env_get(name).is_some()) {
+        Some("env")
+    } else {
+        None
+    };
     let info = serde_json::json!({
         "id": "gemini-oauth",
         "display_name": "Gemini (OAuth)",
         "description": "Gemini via Cloud Code Assist API using OAuth (auth-only plugin)",
         "requires_auth": true,
-        "provider": "google"
+        "provider": "google",
+        "start_check": storage_get(START_CHECK_KEY)
+            .and_then(|raw| serde_json::from_str::<serde_json::Value>(&raw).ok()),
+        "credential_source": if state.credentials.is_some() { "storage" } else { "none" },
+        "project_id_known": project_id_source.is_some(),
+        "project_id_source": project_id_source
     });
 
     let json = info.to_string();
@@ -635,6 +1454,71 @@ pub extern "C" fn provider_auth_logout() -> i32 {
     0
 }
 
+/// Reset all plugin state
+///
+/// Support-recommended recovery when stored state is corrupt or stale:
+/// deletes every storage key this plugin owns, including corrupt-state
+/// backups. Credentials files on disk are left untouched and are re-read on
+/// the next request.
+///
+/// Returns JSON: { "cleared": [keys] }
+#[no_mangle]
+pub extern "C" fn plugin_reset(ret_ptr: i32) -> i32 {
+    let cleared: Vec<String> = STORAGE_KEYS
+        .iter()
+        .map(|key| key.to_string())
+        .chain(storage_keys(CORRUPT_STATE_PREFIX))
+        .filter(|key| storage_delete(key))
+        .collect();
+    log_info(&format!("Plugin state reset (cleared {:?})", cleared));
+
+    let json = serde_json::json!({ "cleared": cleared }).to_string();
+    unsafe {
+        std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
+    }
+    json.len() as i32
+}
+
+/// Preload credentials and project id
+///
+/// Called by tark after install/enable so the first request does not pay for
+/// a token refresh or project discovery.
+///
+/// Returns JSON: { "ready", "authenticated", "project_id_known", "error" }
+#[no_mangle]
+pub extern "C" fn provider_warm(ret_ptr: i32) -> i32 {
+    let summary = match get_valid_token() {
+        Ok(access_token) => {
+            let project_id_known = get_project_id()
+                .or_else(|| discover_project_id(&access_token))
+                .is_some();
+            serde_json::json!({
+                "ready": project_id_known,
+                "authenticated": true,
+                "project_id_known": project_id_known,
+                "error": if project_id_known {
+                    None
+                } else {
+                    Some("Could not determine Google Cloud project ID")
+                }
+            })
+        }
+        Err(e) => serde_json::json!({
+            "ready": false,
+            "authenticated": false,
+            "project_id_known": get_project_id().is_some(),
+            "error": e
+        }),
+    };
+    log_info(&format!("Warm-up finished: {}", summary));
+
+    let json = summary.to_string();
+    unsafe {
+        std::ptr::copy_nonoverlapping(json.as_ptr(), ret_ptr as *mut u8, json.len());
+    }
+    json.len() as i32
+}
+
 /// Get auth credentials for tark's native provider
 ///
 /// This is the key function for auth-only plugins. Instead of implementing
@@ -685,7 +1569,9 @@ pub extern "C" fn provider_auth_credentials(ret_ptr: i32) -> i32 {
     let creds = serde_json::json!({
         "access_token": access_token,
         "project_id": project_id,
-        "api_mode": "cloud_code_assist"
+        "api_mode": "cloud_code_assist",
+        "api_version": api_version(),
+        "endpoint": code_assist_host()
     });
 
     let json = creds.to_string();
@@ -745,9 +1631,257 @@ pub extern "C" fn init_with_credentials(creds_ptr: i32, creds_len: i32) -> i32 {
 
 #[no_mangle]
 pub extern "C" fn get_endpoint(ret_ptr: i32) -> i32 {
-    let endpoint = "https://cloudcode-pa.googleapis.com";
+    let endpoint = code_assist_host();
     unsafe {
         std::ptr::copy_nonoverlapping(endpoint.as_ptr(), ret_ptr as *mut u8, endpoint.len());
     }
     endpoint.len() as i32
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn creds_expiring_at(expiry_date: Option<u64>) -> OAuthCredentials {
+        OAuthCredentials {
+            access_token: "token".to_string(),
+            refresh_token: None,
+            expiry_date,
+            token_type: None,
+            client_id: None,
+            client_secret: None,
+        }
+    }
+
+    #[test]
+    fn expiry_date_is_compared_in_milliseconds() {
+        let now = now_ms();
+        assert!(is_expired(&creds_expiring_at(Some(now - 5_000))));
+        assert!(!is_expired(&creds_expiring_at(Some(now + 60_000))));
+    }
+
+    #[test]
+    fn expiry_date_in_seconds_reads_as_long_expired() {
+        // Gemini CLI writes `expiry_date` in ms; a seconds value is decades in the past.
+        let an_hour_from_now_secs = now_ms() / 1000 + 3600;
+        assert!(is_expired(&creds_expiring_at(Some(an_hour_from_now_secs))));
+    }
+
+    #[test]
+    fn missing_expiry_date_is_not_expired() {
+        assert!(!is_expired(&creds_expiring_at(None)));
+    }
+
+    #[test]
+    fn salvage_state_keeps_tokens_despite_bad_fields() {
+        let raw = r#"{"credentials":{"access_token":"a","refresh_token":"r","expiry_date":"soon"},"project_id":"p"}"#;
+        assert!(serde_json::from_str::<PluginState>(raw).is_err());
+
+        let state = salvage_state(raw).expect("salvageable");
+        let creds = state.credentials.expect("credentials");
+        assert_eq!(creds.access_token, "a");
+        assert_eq!(creds.refresh_token.as_deref(), Some("r"));
+        assert_eq!(creds.expiry_date, None);
+        assert_eq!(state.project_id.as_deref(), Some("p"));
+
+        assert!(salvage_state("not json").is_none());
+    }
+
+    #[test]
+    fn classify_response_maps_statuses() {
+        let wrap = |status: u16| format!(r#"{{"status":{},"headers":[],"body":"b"}}"#, status);
+        assert_eq!(
+            classify_response(&wrap(200)),
+            HttpOutcome::Success("b".into())
+        );
+        assert_eq!(classify_response(&wrap(401)), HttpOutcome::Unauthorized);
+        assert_eq!(classify_response(&wrap(503)), HttpOutcome::Retryable(503));
+        assert_eq!(
+            classify_response(&wrap(400)),
+            HttpOutcome::ClientError {
+                status: 400,
+                body: "b".into()
+            }
+        );
+        assert_eq!(classify_response(&wrap(0)), HttpOutcome::TransportError);
+        assert_eq!(
+            classify_response(r#"{"access_token":"t"}"#),
+            HttpOutcome::Success(r#"{"access_token":"t"}"#.into())
+        );
+    }
+
+    #[test]
+    fn classify_response_reads_retry_after() {
+        let pairs = r#"{"status":429,"headers":[["Retry-After","7"]],"body":""}"#;
+        assert_eq!(
+            classify_response(pairs),
+            HttpOutcome::RateLimited {
+                retry_after: Some(7)
+            }
+        );
+        let object = r#"{"status":429,"headers":{"retry-after":"3"},"body":""}"#;
+        assert_eq!(
+            classify_response(object),
+            HttpOutcome::RateLimited {
+                retry_after: Some(3)
+            }
+        );
+    }
+
+    #[test]
+    fn refresh_errors_distinguish_invalid_grant_from_non_json() {
+        #[derive(Debug, Deserialize)]
+        struct Token {
+            #[allow(dead_code)]
+            access_token: String,
+        }
+        let revoked = r#"{"status":400,"headers":[],"body":"{\"error\":\"invalid_grant\",\"error_description\":\"revoked\"}"}"#;
+        assert!(matches!(
+            parse_token_response::<Token>(revoked),
+            Err(RefreshError::InvalidGrant(d)) if d == "revoked"
+        ));
+        let gateway = r#"{"status":502,"headers":[["Content-Type","text/html"]],"body":"<html>Bad Gateway</html>"}"#;
+        match parse_token_response::<Token>(gateway) {
+            Err(RefreshError::NonJson {
+                status,
+                content_type,
+                body,
+            }) => {
+                assert_eq!(status, 502);
+                assert_eq!(content_type.as_deref(), Some("text/html"));
+                assert_eq!(body, "<html>Bad Gateway</html>");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let ok = r#"{"status":200,"headers":[],"body":"{\"access_token\":\"t\"}"}"#;
+        assert!(parse_token_response::<Token>(ok).is_ok());
+    }
+
+    #[test]
+    fn refresh_errors_keep_google_error_message_whole() {
+        #[derive(Debug, Deserialize)]
+        struct Token {
+            #[allow(dead_code)]
+            access_token: String,
+        }
+        let google = r#"{"status":403,"headers":[],"body":"{\"error\":{\"status\":\"PERMISSION_DENIED\",\"message\":\"Caller lacks permission\"}}"}"#;
+        match parse_token_response::<Token>(google) {
+            Err(RefreshError::OAuth { error, description }) => {
+                assert_eq!(error, "PERMISSION_DENIED");
+                assert_eq!(description.as_deref(), Some("Caller lacks permission"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn body_excerpt_cuts_on_char_boundary() {
+        assert_eq!(body_excerpt("héllo", 2), "h");
+        assert_eq!(body_excerpt("short", 500), "short");
+    }
+
+    #[test]
+    fn fs_allowlist_matches_files_and_directories() {
+        let allowlist = vec![
+            GEMINI_CLI_OAUTH2_PATHS[0].to_string(),
+            "/opt/gemini/".to_string(),
+        ];
+        assert!(path_in_allowlist(GEMINI_CLI_OAUTH2_PATHS[0], &allowlist));
+        assert!(path_in_allowlist("/opt/gemini/oauth2.js", &allowlist));
+        assert!(!path_in_allowlist(GEMINI_CLI_OAUTH2_PATHS[1], &allowlist));
+        assert!(!path_in_allowlist(
+            "/opt/gemini/../../etc/passwd",
+            &allowlist
+        ));
+    }
+
+    #[test]
+    fn api_version_must_be_a_single_segment() {
+        assert!(is_valid_api_version("v1internal"));
+        assert!(is_valid_api_version("v1beta"));
+        assert!(!is_valid_api_version("v1/../x"));
+        assert!(!is_valid_api_version("beta"));
+        assert!(!is_valid_api_version(""));
+    }
+
+    #[test]
+    fn endpoint_env_wins_over_file() {
+        let config: EndpointConfig =
+            serde_json::from_str(r#"{"api_endpoint":"https://proxy.example","discovery_url":""}"#)
+                .unwrap();
+        assert_eq!(
+            pick_endpoint(
+                Some("http://localhost:9000".to_string()),
+                config.api_endpoint.clone(),
+                CODE_ASSIST_HOST
+            ),
+            "http://localhost:9000"
+        );
+        assert_eq!(
+            pick_endpoint(None, config.api_endpoint, CODE_ASSIST_HOST),
+            "https://proxy.example"
+        );
+        assert_eq!(
+            pick_endpoint(None, config.discovery_url, TOKEN_URL),
+            TOKEN_URL
+        );
+    }
+
+    #[test]
+    fn refresh_extra_params_are_decoded_and_filtered() {
+        assert_eq!(
+            parse_extra_params("audience=https%3A%2F%2Fapi.example.com&resource=a+b"),
+            vec![
+                (
+                    "audience".to_string(),
+                    "https://api.example.com".to_string()
+                ),
+                ("resource".to_string(), "a b".to_string()),
+            ]
+        );
+        assert!(parse_extra_params("grant_type=password&novalue&bad=%zz").is_empty());
+        assert_eq!(
+            urlencoding_encode("https://api.example.com"),
+            "https%3A%2F%2Fapi.example.com"
+        );
+    }
+
+    #[test]
+    fn expired_token_is_refreshed_against_mock_endpoint() {
+        let mock_url = "http://127.0.0.1:8799/token";
+        MOCK_ENV.with(|env| {
+            env.borrow_mut()
+                .insert("GEMINI_TOKEN_URL".into(), mock_url.into())
+        });
+        save_state(&PluginState {
+            credentials: Some(OAuthCredentials {
+                access_token: "old-access".to_string(),
+                refresh_token: Some("old-refresh".to_string()),
+                expiry_date: Some(1),
+                token_type: Some("Bearer".to_string()),
+                client_id: Some("client".to_string()),
+                client_secret: Some("secret".to_string()),
+            }),
+            project_id: None,
+        });
+        MOCK_HTTP.with(|http| {
+            *http.borrow_mut() = Some(Box::new(move |url, body| {
+                assert_eq!(url, mock_url);
+                assert!(body.contains("refresh_token=old-refresh"));
+                serde_json::json!({
+                    "status": 200,
+                    "headers": [],
+                    "body": r#"{"access_token":"new-access","expires_in":3600}"#
+                })
+                .to_string()
+            }))
+        });
+
+        assert_eq!(get_valid_token().as_deref(), Ok("new-access"));
+
+        let stored = load_state().credentials.expect("stored credentials");
+        assert_eq!(stored.access_token, "new-access");
+        assert_eq!(stored.refresh_token.as_deref(), Some("old-refresh"));
+        assert!(!is_expired(&stored));
+    }
+}